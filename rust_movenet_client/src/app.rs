@@ -1,67 +1,773 @@
-use crate::camera::Camera;
+use crate::buffer::UserBuffer;
+use crate::camera::{is_device_lost, is_timeout, monotonic_micros, Camera, CameraOptions, FrameInfo, Memory, SequenceGaps};
+use crate::config::{CaptureFormat, Config, MemoryArg, SourceKind};
+use crate::controls::ControlProfile;
+use crate::crop::{self, CropTracker};
+use crate::discovery::{self, Device};
+use crate::format::{FourCc, Rect};
+use crate::overlay;
+use crate::preprocess;
 use crate::server_facing::ServerFacing;
+use crate::source::{is_end_of_stream, FileSource, FrameSource, ImageSource, PatternSource};
 
+use opencv::core::Mat;
 use opencv::highgui;
-use std::time::Instant;
+use rust_movenet_protocol::{ClientHello, FrameHeader, Letterbox, PixelFormat, ResponseMode, ServerMessage, PROTOCOL_VERSION};
+use std::collections::BTreeMap;
+use std::os::fd::AsRawFd;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::sync::mpsc::{channel, sync_channel, Receiver, RecvTimeoutError, Sender, SyncSender, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const MODEL_INPUT_SIZE: u32 = 192;
+/// Keypoints less confident than this are neither drawn nor followed.
+const KEYPOINT_THRESHOLD: f32 = 0.25;
+/// Bytes kept free in front of each USERPTR capture buffer for the framed `FrameHeader`.
+const HEADER_ROOM: usize = 256;
+/// How often to look for the camera again after it was lost.
+const RECONNECT_INTERVAL: Duration = Duration::from_millis(500);
+
+type StageResult = Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+/// A frame on its way from the capture stage to the send stage.
+struct OutgoingFrame {
+    header: FrameHeader,
+    payload: Payload,
+}
+
+enum Payload {
+    Copied(Vec<u8>),
+    /// A USERPTR capture buffer, sent in place and then handed back to the capture stage.
+    Captured(UserBuffer),
+}
+
+/// The capture stage's ends of the pipeline channels.
+struct CaptureChannels {
+    credits: Receiver<()>,
+    /// Hands back the credit of a frame that is skipped after it was taken.
+    refunds: SyncSender<()>,
+    frames: Sender<OutgoingFrame>,
+    pending: Sender<PendingFrame>,
+    /// Empty capture buffers coming back from the send stage, when capturing zero-copy.
+    spares: Option<Receiver<UserBuffer>>,
+}
+
+/// What the render stage keeps for a frame until its result comes back.
+struct PendingFrame {
+    header: FrameHeader,
+    image: Option<Mat>,
+    /// The part of the captured frame that was sent, which the keypoints are normalized to.
+    crop: Rect,
+}
+
+/// Frames that never made it to the server, reported along with the frame rate.
+#[derive(Default)]
+struct DropCounts {
+    /// Skipped by the capture stage, because too many frames were in flight or a JPEG could
+    /// not be decoded.
+    skipped: AtomicU64,
+    /// Dropped by the driver, as gaps in its sequence numbers show, or delivered corrupt.
+    by_driver: AtomicU64,
+}
+
+impl DropCounts {
+    /// Counts the frames the driver dropped before the one described by `info`, and that frame
+    /// itself if it is corrupt. Returns whether the frame can be used.
+    fn check_delivered(&self, gaps: &mut SequenceGaps, info: &FrameInfo) -> bool {
+        let missed = gaps.record(info.sequence) as u64 + info.is_corrupt() as u64;
+        self.by_driver.fetch_add(missed, Ordering::Relaxed);
+        !info.is_corrupt()
+    }
+}
+
+/// How the capture stage turns camera buffers into outgoing frames.
+#[derive(Clone, Copy)]
+struct CaptureSettings {
+    width: u32,
+    height: u32,
+    /// Bytes per captured row, as reported by the driver.
+    stride: u32,
+    pixel_format: PixelFormat,
+    /// Set when the client letterboxes frames itself and only sends the model tensor.
+    letterbox: Option<Letterbox>,
+    /// Clockwise rotation applied to frames before they are letterboxed.
+    rotation: u32,
+    /// Whether the render stage needs a BGR copy of each frame to draw on.
+    keep_local: bool,
+    /// Whether full frames are sent straight from USERPTR capture buffers.
+    zero_copy: bool,
+}
+
+/// How the camera was set up, so it can be set up the same way after it is replugged.
+struct CameraSetup {
+    options: CameraOptions,
+    crop: Option<Rect>,
+    fps: Option<u32>,
+    export_dmabuf: bool,
+    controls: Option<ControlProfile>,
+}
+
+impl CameraSetup {
+    /// Opens `device` and applies the sensor crop, DMABUF export, control profile and frame rate.
+    fn open(&self, device: &str) -> Result<Camera, Box<dyn std::error::Error>> {
+        let mut camera = Camera::new(device, &self.options)?;
+        // The crop can change the frame size, so it goes before anything that depends on it.
+        if let Some(crop) = self.crop {
+            let applied = camera.set_crop(crop)?;
+            println!("Requested sensor crop {}, cropping to {} (bounds {})", crop, applied, camera.crop_bounds()?);
+            // The crop released the buffers; map the new ones now so they are the ones exported.
+            camera.map_buffers()?;
+        }
+        if self.export_dmabuf {
+            camera.export_buffers()?;
+            for (index, planes) in camera.buffers.iter().enumerate() {
+                for (plane, buffer) in planes.iter().enumerate() {
+                    if let Some(dmabuf) = &buffer.dmabuf {
+                        println!("Buffer {} plane {} exported as DMABUF fd {} ({} bytes)", index, plane, dmabuf.as_raw_fd(), buffer.length);
+                    }
+                }
+            }
+        }
+        if let Some(profile) = &self.controls {
+            let available = camera.controls()?;
+            let values = profile.resolve(&available)?;
+            camera.set_controls(&values)?;
+            for (control, _) in values {
+                println!("{} = {}", control.key(), camera.control(control)?);
+            }
+        }
+        if let Some(fps) = self.fps {
+            let applied = camera.set_frame_rate(fps)?;
+            println!("Requested {} FPS, capturing at {} (supported: {})", fps, applied, camera.frame_intervals()?);
+        } else if let Some(interval) = camera.frame_interval()? {
+            println!("Capturing at {}", interval);
+        }
+        Ok(camera)
+    }
+}
+
+/// Finds and reopens the camera after it was unplugged or glitched.
+struct Reconnect {
+    setup: CameraSetup,
+    /// The node the camera was last opened from.
+    device: String,
+    /// Where the camera is plugged in, to find it again when it comes back as another node.
+    bus_info: String,
+}
+
+impl Reconnect {
+    /// The node the camera is on now, if it is back: the old node if it still belongs to the
+    /// same bus, otherwise whichever capture node does.
+    fn find_camera(&self) -> Option<String> {
+        let same_camera = |path: &str| Device::open(path).is_ok_and(|d| d.can_stream() && d.capabilities.bus_info == self.bus_info);
+        if same_camera(&self.device) {
+            return Some(self.device.clone());
+        }
+        // Without a bus to match on, another node could be any camera.
+        if self.bus_info.is_empty() {
+            return None;
+        }
+        discovery::video_nodes().into_iter().find(|path| same_camera(path))
+    }
+
+    /// Waits for the camera to come back and resumes streaming from it with the same format
+    /// and controls. Returns `false` if the pipeline stopped first.
+    fn reopen(&mut self, camera: &mut Box<dyn FrameSource>, running: &AtomicBool) -> Result<bool, Box<dyn std::error::Error>> {
+        let format = camera.format();
+        // A camera that glitched but stayed plugged in refuses to be set up again while the
+        // old fd still holds its buffers. On an unplugged one this only unmaps them.
+        camera.stop().ok();
+        while running.load(Ordering::SeqCst) {
+            thread::sleep(RECONNECT_INTERVAL);
+            let Some(device) = self.find_camera() else { continue };
+            // A freshly plugged camera can still be initializing, so failures are retried.
+            let mut reopened = match self.setup.open(&device) {
+                Ok(reopened) => reopened,
+                Err(e) => {
+                    println!("Failed to reopen {}: {}", device, e);
+                    continue;
+                }
+            };
+            if reopened.format() != format {
+                return Err(format!("{} came back as {}, not {}", device, reopened.format(), format).into());
+            }
+            reopened.start_streaming()?;
+            println!("Camera is back on {}", device);
+            *camera = Box::new(reopened);
+            self.device = device;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+}
 
 pub struct App {
     server: ServerFacing,
-    camera: Camera,
+    source: Box<dyn FrameSource>,
+    /// How to get the camera back if it is lost; `None` for the other sources.
+    reconnect: Option<Reconnect>,
+    capture: CaptureSettings,
+    /// Where to crop frames next when following the subject.
+    follow: Option<Mutex<CropTracker>>,
+    /// Maximum number of frames sent to the server whose results have not come back yet.
+    max_in_flight: usize,
+    /// Preview window title, or `None` when running headless.
+    window: Option<String>,
 }
 
 impl App {
-    pub fn new(server_address: &str) -> Result<Self, Box<dyn std::error::Error>> {
-        let server = ServerFacing::new(server_address)?;
-        let camera = Camera::new("/dev/video0")?;
-        Ok(App { server, camera })
+    pub fn new(config: &Config) -> Result<Self, Box<dyn std::error::Error>> {
+        let response_mode = ResponseMode::from(config.response_mode);
+        if config.preprocess && response_mode != ResponseMode::KeypointsOnly {
+            return Err("--preprocess only works with --response-mode keypoints-only".into());
+        }
+        if config.follow && config.format == CaptureFormat::Mjpeg {
+            return Err("--follow cannot crop MJPEG frames; capture YUYV or NV12".into());
+        }
+        if config.follow && !response_mode.includes_keypoints() {
+            return Err("--follow needs keypoints from the server; use --response-mode keypoints-only or both".into());
+        }
+
+        // Open the source first: the hello describes the format it actually delivers.
+        let (source, reconnect): (Box<dyn FrameSource>, _) = match config.source {
+            SourceKind::Camera => {
+                let (camera, reconnect) = open_camera(config)?;
+                (Box::new(camera), Some(reconnect))
+            }
+            kind => (open_stand_in(kind, config)?, None),
+        };
+        let applied = source.format();
+
+        let (width, height, pixel_format) = if config.preprocess {
+            (MODEL_INPUT_SIZE, MODEL_INPUT_SIZE, PixelFormat::Bgr24)
+        } else {
+            (applied.width, applied.height, config.format.pixel_format())
+        };
+        let hello = ClientHello {
+            version: PROTOCOL_VERSION,
+            client_name: config.client_name.clone(),
+            width,
+            height,
+            pixel_format,
+            response_mode,
+        };
+        let server = ServerFacing::new(&config.server, &hello)?;
+
+        // Tensors have to come out the way the server would have turned full frames.
+        let rotation = server.capabilities.rotation;
+        let letterbox = config.preprocess.then(|| tensor_letterbox(applied.width, applied.height, rotation, server.capabilities.flip));
+        let capture = CaptureSettings {
+            width: applied.width,
+            height: applied.height,
+            stride: applied.bytes_per_line,
+            pixel_format: config.format.pixel_format(),
+            letterbox,
+            rotation,
+            keep_local: !config.headless && !response_mode.includes_jpeg(),
+            // Tensors and crops are built fresh for every frame, so only full frames can skip
+            // the copy.
+            zero_copy: config.source == SourceKind::Camera && config.memory != MemoryArg::Mmap && letterbox.is_none() && !config.follow,
+        };
+        // Never crop below the model input, which would only blow up the pixels.
+        let follow = config.follow.then(|| Mutex::new(CropTracker::new(applied.width, applied.height, MODEL_INPUT_SIZE)));
+        let window = (!config.headless).then(|| config.window_title.clone());
+        Ok(App { server, source, reconnect, capture, follow, max_in_flight: config.max_in_flight.max(1), window })
     }
 
+    /// Runs capture, send and receive on their own threads and renders on this one, so up to
+    /// `max_in_flight` frames can be on the network at once.
     pub fn run(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        self.camera.start_streaming()?;
-        
-        opencv::highgui::named_window("MoveNet (CPSC 429)", opencv::highgui::WINDOW_AUTOSIZE)?;
+        self.source.start()?;
 
-        let mut frame_count = 0;
-        let start_time = Instant::now();
+        if let Some(title) = &self.window {
+            opencv::highgui::named_window(title, opencv::highgui::WINDOW_AUTOSIZE)?;
+        }
 
-        loop {
-            let frame = self.camera.get_frame()?;
-            self.server.send_image(frame)?;
-            let (_inference_result, rgb_image) = self.server.receive_result()?;
-            self.render(&rgb_image)?;
+        let running = AtomicBool::new(true);
+        let drops = DropCounts::default();
+        let (credit_sender, credit_receiver) = sync_channel(self.max_in_flight);
+        for _ in 0..self.max_in_flight {
+            credit_sender.send(()).unwrap();
+        }
+        let (frame_sender, frame_receiver) = channel();
+        let (pending_sender, pending_receiver) = channel();
+        let (result_sender, result_receiver) = channel();
+        let (spare_sender, spare_receiver) = channel();
+        let zero_copy = self.capture.zero_copy;
+        if zero_copy {
+            // One spare per frame that can be in flight; each comes back once it has been sent.
+            for _ in 0..self.max_in_flight {
+                spare_sender.send(UserBuffer::new(HEADER_ROOM, self.source.format().size_image as usize)).unwrap();
+            }
+        }
+        let channels = CaptureChannels {
+            credits: credit_receiver,
+            refunds: credit_sender.clone(),
+            frames: frame_sender,
+            pending: pending_sender,
+            spares: zero_copy.then_some(spare_receiver),
+        };
+
+        let mut sender = self.server.try_clone()?;
+        let mut receiver = self.server.try_clone()?;
+        let settings = self.capture;
+        let follow = self.follow.as_ref();
+        let source = &mut self.source;
+        let reconnect = &mut self.reconnect;
+        let server = &self.server;
+        let window = self.window.as_deref();
+
+        let stage_results = thread::scope(|scope| {
+            let capture = scope.spawn(|| {
+                let result = capture_frames(source, reconnect, settings, follow, &running, &drops, channels);
+                running.store(false, Ordering::SeqCst);
+                result
+            });
+            let send = scope.spawn(|| {
+                let result = send_frames(&mut sender, frame_receiver, zero_copy.then_some(spare_sender));
+                running.store(false, Ordering::SeqCst);
+                result
+            });
+            let receive = scope.spawn(|| receive_results(&mut receiver, &running, credit_sender, result_sender));
+
+            let rendered = render_results(window, follow, &running, &drops, pending_receiver, result_receiver);
+
+            running.store(false, Ordering::SeqCst);
+            server.shutdown().ok();
+            [rendered, join_stage(capture), join_stage(send), join_stage(receive)]
+        });
+
+        self.source.stop()?;
+        for result in stage_results {
+            result.map_err(|e| e.to_string())?;
+        }
+        Ok(())
+    }
+}
+
+/// Opens the camera, picking one if no `--device` was given, and checks it captures in the
+/// requested format.
+fn open_camera(config: &Config) -> Result<(Camera, Reconnect), Box<dyn std::error::Error>> {
+    let device = discovery::device_or_pick(config.device.as_deref(), config.format.fourcc())?;
+    let frame_timeout = config.frame_timeout_ms.map(Duration::from_millis);
+    let memory = match config.memory {
+        MemoryArg::Mmap => Memory::Mmap,
+        MemoryArg::Userptr => Memory::UserPtr { header_room: HEADER_ROOM },
+    };
+    let options = CameraOptions {
+        buffer_count: config.buffers,
+        width: config.width,
+        height: config.height,
+        fourcc: config.format.fourcc(),
+        timeout: frame_timeout,
+        memory,
+    };
+    let setup = CameraSetup {
+        options,
+        crop: config.crop,
+        fps: config.fps,
+        export_dmabuf: config.export_dmabuf,
+        controls: config.controls.as_deref().map(ControlProfile::load).transpose()?,
+    };
+    let camera = setup.open(&device)?;
+    let applied = camera.format();
+    if applied.fourcc != config.format.fourcc() {
+        let supported: Vec<String> = camera.formats()?.iter().map(|f| FourCc(f.fourcc).to_string()).collect();
+        return Err(format!("{} does not support {:?} capture (supported: {})", device, config.format, supported.join(", ")).into());
+    }
+    if (applied.width, applied.height) != (config.width, config.height) {
+        println!(
+            "Requested {}x{}, capturing {}x{} (supported: {})",
+            config.width,
+            config.height,
+            applied.width,
+            applied.height,
+            camera.frame_sizes(applied.fourcc)?
+        );
+    }
+    let bus_info = camera.capabilities().bus_info.clone();
+    Ok((camera, Reconnect { setup, device, bus_info }))
+}
+
+/// Opens one of the sources that stand in for a camera.
+fn open_stand_in(kind: SourceKind, config: &Config) -> Result<Box<dyn FrameSource>, Box<dyn std::error::Error>> {
+    if config.format != CaptureFormat::Yuyv {
+        return Err(format!("Only cameras capture {:?}; other sources deliver YUYV", config.format).into());
+    }
+    let path = || config.source_path.as_deref().ok_or("--source file and --source images need --source-path");
+    let (width, height, fps) = (config.width, config.height, config.fps);
+    let source: Box<dyn FrameSource> = match kind {
+        SourceKind::File => Box::new(FileSource::open(path()?, width, height, fps, config.source_loop)?),
+        SourceKind::Images => Box::new(ImageSource::open(path()?, width, height, fps, config.source_loop)?),
+        SourceKind::Pattern => Box::new(PatternSource::new(width, height, fps)?),
+        SourceKind::Camera => unreachable!("cameras are opened by open_camera"),
+    };
+    println!("Using a {:?} source: {}", kind, source.format());
+    Ok(source)
+}
 
-            frame_count += 1;
-            if frame_count % 30 == 0 {
-                let elapsed = start_time.elapsed();
-                let fps = frame_count as f64 / elapsed.as_secs_f64();
-                println!("FPS: {:.2}", fps);
+fn join_stage(handle: thread::ScopedJoinHandle<'_, StageResult>) -> StageResult {
+    handle.join().unwrap_or_else(|_| Err("pipeline stage panicked".into()))
+}
+
+/// Takes frames from the source for as long as the pipeline runs. A frame is only sent when
+/// an in-flight credit is available; otherwise it is dropped so the server always gets the
+/// newest frame. Frames the driver dropped or corrupted are counted in `drops`. A lost camera
+/// is reopened without interrupting the other stages. When following the subject, frames are
+/// cropped to where `follow` last saw it.
+fn capture_frames(
+    source: &mut Box<dyn FrameSource>,
+    reconnect: &mut Option<Reconnect>,
+    settings: CaptureSettings,
+    follow: Option<&Mutex<CropTracker>>,
+    running: &AtomicBool,
+    drops: &DropCounts,
+    channels: CaptureChannels,
+) -> StageResult {
+    let mut sequence = 0u64;
+    let mut gaps = SequenceGaps::default();
+    // A spare that went unused because capture failed, to try again with.
+    let mut unused_spare: Option<UserBuffer> = None;
+    // A source error, recovered from at the top of the loop once the failed call no longer
+    // borrows the source.
+    let mut failure: Option<Box<dyn std::error::Error>> = None;
+    while running.load(Ordering::SeqCst) {
+        if let Some(error) = failure.take() {
+            if recover(error, source, reconnect, running)? {
+                continue;
+            }
+            break;
+        }
+        if let Some(spares) = &channels.spares {
+            // The buffer is handed off as soon as it is dequeued, so take the credit first.
+            match channels.credits.try_recv() {
+                Ok(()) => {}
+                Err(TryRecvError::Empty) => {
+                    match source.next_frame() {
+                        Ok(frame) => {
+                            if drops.check_delivered(&mut gaps, &frame.info) {
+                                drops.skipped.fetch_add(1, Ordering::Relaxed);
+                            }
+                        }
+                        Err(e) => failure = Some(e),
+                    }
+                    continue;
+                }
+                Err(TryRecvError::Disconnected) => break,
+            }
+            let Some(mut buffer) = unused_spare.take().or_else(|| spares.recv().ok()) else { break };
+            let info = match source.take_frame(&mut buffer) {
+                Ok(info) => info,
+                Err(e) => {
+                    // Neither the credit nor the buffer went to a frame.
+                    channels.refunds.try_send(()).ok();
+                    unused_spare = Some(buffer);
+                    failure = Some(e);
+                    continue;
+                }
+            };
+            if !drops.check_delivered(&mut gaps, &info) {
+                channels.refunds.try_send(()).ok();
+                unused_spare = Some(buffer);
+                continue;
             }
 
-            if highgui::wait_key(1)? > 0 {
+            let header = frame_header(&settings, sequence, info.timestamp_us);
+            sequence += 1;
+            let image = if settings.keep_local { local_image(&[buffer.image()], &header)? } else { None };
+            let outgoing = OutgoingFrame { header, payload: Payload::Captured(buffer) };
+            let crop = Rect::full(header.width, header.height);
+            if channels.pending.send(PendingFrame { header, image, crop }).is_err() || channels.frames.send(outgoing).is_err() {
                 break;
             }
+            continue;
         }
 
-        self.camera.stop_streaming()?;
-        Ok(())
+        let frame = match source.next_frame() {
+            Ok(frame) => frame,
+            Err(e) => {
+                failure = Some(e);
+                continue;
+            }
+        };
+        if !drops.check_delivered(&mut gaps, &frame.info) {
+            continue;
+        }
+        match channels.credits.try_recv() {
+            Ok(()) => {}
+            Err(TryRecvError::Empty) => {
+                drops.skipped.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            Err(TryRecvError::Disconnected) => break,
+        }
+
+        let mut header = frame_header(&settings, sequence, frame.info.timestamp_us);
+        sequence += 1;
+
+        let full_frame = Rect::full(header.width, header.height);
+        let crop = follow.map_or(full_frame, |tracker| tracker.lock().unwrap().crop());
+        let cropped = match (crop != full_frame).then(|| crop::crop_frame(&frame.planes, &header, crop)).transpose() {
+            Ok(cropped) => cropped,
+            Err(e) => {
+                println!("Skipping frame {}: {}", header.sequence, e);
+                channels.refunds.try_send(()).ok();
+                drops.skipped.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+        };
+        let planes = match &cropped {
+            Some((cropped_header, image)) => {
+                header = *cropped_header;
+                vec![&image[..]]
+            }
+            None => frame.planes.clone(),
+        };
+
+        let (outgoing, image) = match settings.letterbox {
+            Some(letterbox) => {
+                let letterbox = if cropped.is_some() {
+                    tensor_letterbox(crop.width, crop.height, settings.rotation, letterbox.flipped)
+                } else {
+                    letterbox
+                };
+                let bgr = match preprocess::to_bgr(&planes, &header) {
+                    Ok(bgr) => bgr,
+                    // Cameras occasionally deliver a truncated JPEG; skip it rather than stop.
+                    Err(e) if header.pixel_format == PixelFormat::Mjpeg => {
+                        println!("Skipping frame {}: {}", header.sequence, e);
+                        channels.refunds.try_send(()).ok();
+                        drops.skipped.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+                let tensor = preprocess::letterbox_tensor(&bgr, settings.rotation, &letterbox)?;
+                let tensor_header = FrameHeader {
+                    width: letterbox.target_width,
+                    height: letterbox.target_height,
+                    stride: letterbox.target_width * 3,
+                    pixel_format: PixelFormat::Bgr24,
+                    letterbox: Some(letterbox),
+                    ..header
+                };
+                (OutgoingFrame { header: tensor_header, payload: Payload::Copied(tensor) }, settings.keep_local.then_some(bgr))
+            }
+            None => {
+                let image = if settings.keep_local { local_image(&planes, &header)? } else { None };
+                (OutgoingFrame { header, payload: Payload::Copied(preprocess::pack_planes(&planes, &header)) }, image)
+            }
+        };
+        // Everything needed has been copied out, so give the buffer back to the driver now.
+        drop(frame);
+
+        // Register the frame with the render stage before its result can possibly arrive.
+        if channels.pending.send(PendingFrame { header, image, crop }).is_err() || channels.frames.send(outgoing).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Converts a frame for the render stage. A JPEG that fails to decode is not drawn, but is
+/// still sent; the server reports it as corrupt.
+fn local_image(planes: &[&[u8]], header: &FrameHeader) -> opencv::Result<Option<Mat>> {
+    match preprocess::to_bgr(planes, header) {
+        Ok(image) => Ok(Some(image)),
+        Err(_) if header.pixel_format == PixelFormat::Mjpeg => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// How a `width`x`height` frame fits the model input once it is rotated clockwise by
+/// `rotation` degrees.
+fn tensor_letterbox(width: u32, height: u32, rotation: u32, flip: bool) -> Letterbox {
+    let (width, height) = if rotation % 180 == 90 { (height, width) } else { (width, height) };
+    Letterbox::new(width, height, MODEL_INPUT_SIZE, MODEL_INPUT_SIZE, flip)
+}
+
+fn frame_header(settings: &CaptureSettings, sequence: u64, capture_timestamp_us: u64) -> FrameHeader {
+    FrameHeader {
+        sequence,
+        capture_timestamp_us,
+        width: settings.width,
+        height: settings.height,
+        stride: settings.stride,
+        pixel_format: settings.pixel_format,
+        letterbox: None,
+    }
+}
+
+/// Handles a source error in the capture stage. Returns `true` to keep capturing once a lost
+/// camera is back, and `false` to stop because the source ran out of frames or a shutdown was
+/// requested meanwhile. A timeout is only an error while the pipeline is still running.
+fn recover(
+    error: Box<dyn std::error::Error>,
+    source: &mut Box<dyn FrameSource>,
+    reconnect: &mut Option<Reconnect>,
+    running: &AtomicBool,
+) -> Result<bool, Box<dyn std::error::Error + Send + Sync>> {
+    if is_end_of_stream(&*error) {
+        println!("{}", error);
+        return Ok(false);
+    }
+    if is_timeout(&*error) && !running.load(Ordering::SeqCst) {
+        return Ok(false);
+    }
+    let Some(reconnect) = reconnect else { return Err(error.to_string().into()) };
+    // Some drivers just stop delivering frames when the camera goes, and the node disappears.
+    let node_gone = is_timeout(&*error) && !Path::new(&reconnect.device).exists();
+    if !is_device_lost(&*error) && !node_gone {
+        return Err(error.to_string().into());
     }
+    println!("Lost the camera: {}; waiting for it to come back", error);
+    reconnect.reopen(source, running).map_err(|e| e.to_string().into())
+}
+
+fn send_frames(server: &mut ServerFacing, frames: Receiver<OutgoingFrame>, spares: Option<Sender<UserBuffer>>) -> StageResult {
+    for frame in frames {
+        match frame.payload {
+            Payload::Copied(bytes) => server.send_image(&frame.header, &bytes)?,
+            Payload::Captured(mut buffer) => {
+                server.send_image_in_place(&frame.header, &mut buffer)?;
+                if let Some(spares) = &spares {
+                    spares.send(buffer).ok();
+                }
+            }
+        }
+    }
+    Ok(())
+}
 
-    fn render(&self, rgb_frame: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
-        // Decode the image (JPEG compressed)
-        let img = match opencv::imgcodecs::imdecode(&opencv::core::Vector::from_slice(rgb_frame), opencv::imgcodecs::IMREAD_COLOR) {
-            Ok(img) => img,
+fn receive_results(
+    server: &mut ServerFacing,
+    running: &AtomicBool,
+    credits: SyncSender<()>,
+    results: Sender<(ServerMessage, Option<Vec<u8>>)>,
+) -> StageResult {
+    loop {
+        match server.receive_result() {
+            Ok(result) => {
+                credits.try_send(()).ok();
+                if results.send(result).is_err() {
+                    return Ok(());
+                }
+            }
+            // A read error after shutdown is just the connection being closed under us.
+            Err(_) if !running.load(Ordering::SeqCst) => return Ok(()),
             Err(e) => {
-                println!("Failed to decode image: {:?}", e);
-                return Ok(());
+                running.store(false, Ordering::SeqCst);
+                return Err(e.into());
             }
+        }
+    }
+}
+
+/// Matches results to their frames by sequence number and displays them in `window`, if any.
+/// Each result also moves the crop when following the subject.
+fn render_results(
+    window: Option<&str>,
+    follow: Option<&Mutex<CropTracker>>,
+    running: &AtomicBool,
+    drops: &DropCounts,
+    pending_receiver: Receiver<PendingFrame>,
+    results: Receiver<(ServerMessage, Option<Vec<u8>>)>,
+) -> StageResult {
+    let mut pending = BTreeMap::new();
+    let mut frame_count = 0;
+    let start_time = Instant::now();
+    let mut total_latency_us = 0u64;
+    let mut total_server_us = 0u64;
+    let mut total_inference_us = 0u64;
+    let mut server_dropped = 0u64;
+
+    while running.load(Ordering::SeqCst) {
+        if window.is_some() && highgui::wait_key(1)? > 0 {
+            break;
+        }
+
+        let (message, jpeg) = match results.recv_timeout(Duration::from_millis(10)) {
+            Ok(message) => message,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
         };
+        for frame in pending_receiver.try_iter() {
+            pending.insert(frame.header.sequence, frame);
+        }
 
-        // Display the image
-        opencv::highgui::imshow("MoveNet (CPSC 429)", &img)?;
+        let inference_result = match message {
+            ServerMessage::Result(result) => result,
+            // Drop notices can overtake results still being processed, so they only
+            // retire their own frame.
+            ServerMessage::Dropped { sequence, .. } => {
+                pending.remove(&sequence);
+                server_dropped += 1;
+                continue;
+            }
+        };
 
-        // Process GUI events
-        opencv::highgui::wait_key(1)?;
+        // Results come back in order, so anything older than this one will never get a reply.
+        let Some(frame) = pending.remove(&inference_result.sequence) else {
+            println!("Result for unknown frame {}", inference_result.sequence);
+            continue;
+        };
+        pending = pending.split_off(&inference_result.sequence);
 
-        Ok(())
+        if let Some(tracker) = follow {
+            tracker.lock().unwrap().update(&inference_result.keypoints, frame.crop, KEYPOINT_THRESHOLD);
+        }
+
+        if let Some(title) = window {
+            match (jpeg, frame.image) {
+                (Some(jpeg), _) => render_jpeg(title, &jpeg)?,
+                (None, Some(mut image)) => {
+                    overlay::draw_pose(&mut image, &inference_result.keypoints, KEYPOINT_THRESHOLD)?;
+                    render(title, &image)?;
+                }
+                (None, None) => {}
+            }
+        }
+
+        total_latency_us += monotonic_micros().saturating_sub(frame.header.capture_timestamp_us);
+        total_server_us += inference_result.sent_at_us.saturating_sub(inference_result.received_at_us);
+        total_inference_us += inference_result.inferred_at_us.saturating_sub(inference_result.received_at_us);
+
+        frame_count += 1;
+        if frame_count % 30 == 0 {
+            let elapsed = start_time.elapsed();
+            let fps = frame_count as f64 / elapsed.as_secs_f64();
+            println!(
+                "FPS: {:.2}, capture-to-display: {:.1} ms, server: {:.1} ms (inference {:.1} ms), dropped by driver: {}, skipped at capture: {}, dropped by server: {}",
+                fps,
+                total_latency_us as f64 / frame_count as f64 / 1000.0,
+                total_server_us as f64 / frame_count as f64 / 1000.0,
+                total_inference_us as f64 / frame_count as f64 / 1000.0,
+                drops.by_driver.load(Ordering::Relaxed),
+                drops.skipped.load(Ordering::Relaxed),
+                server_dropped,
+            );
+        }
     }
+    Ok(())
+}
+
+fn render_jpeg(title: &str, rgb_frame: &[u8]) -> opencv::Result<()> {
+    // Decode the image (JPEG compressed)
+    let img = match opencv::imgcodecs::imdecode(&opencv::core::Vector::from_slice(rgb_frame), opencv::imgcodecs::IMREAD_COLOR) {
+        Ok(img) => img,
+        Err(e) => {
+            println!("Failed to decode image: {:?}", e);
+            return Ok(());
+        }
+    };
+
+    render(title, &img)
+}
+
+fn render(title: &str, img: &Mat) -> opencv::Result<()> {
+    // Display the image
+    opencv::highgui::imshow(title, img)
 }
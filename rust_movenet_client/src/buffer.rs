@@ -1,6 +1,134 @@
+use nix::sys::mman::munmap;
+use std::alloc::{alloc_zeroed, dealloc, Layout};
+use std::os::fd::OwnedFd;
 use std::ptr::NonNull;
 
+/// Who owns the memory behind a [`Buffer`], which decides how it is released.
+pub enum Ownership {
+    /// Driver memory mapped with `mmap` (`V4L2_MEMORY_MMAP`); unmapped on release.
+    Mapped,
+    /// Application memory lent to the driver (`V4L2_MEMORY_USERPTR`); freed on release.
+    User(UserBuffer),
+}
+
 pub struct Buffer {
     pub start: NonNull<u8>,
     pub length: usize,
+    /// The buffer exported as a DMABUF, if the camera was asked to export its buffers. Closed
+    /// together with the mapping.
+    pub dmabuf: Option<OwnedFd>,
+    pub ownership: Ownership,
+}
+
+impl Buffer {
+    /// Wraps a user buffer so it can be queued with `V4L2_MEMORY_USERPTR`.
+    pub fn user(mut buffer: UserBuffer) -> Self {
+        Buffer {
+            start: buffer.image_ptr(),
+            length: buffer.capacity(),
+            dmabuf: None,
+            ownership: Ownership::User(buffer),
+        }
+    }
+
+    /// Unmaps or frees the memory. The driver must no longer be using it.
+    pub fn release(self) {
+        match self.ownership {
+            Ownership::Mapped => unsafe {
+                munmap(self.start.cast::<libc::c_void>(), self.length).ok();
+            },
+            Ownership::User(buffer) => drop(buffer),
+        }
+    }
+}
+
+// The mapping is owned by the `Camera` holding the buffer, so moving it to another
+// thread together with the camera is sound.
+unsafe impl Send for Buffer {}
+
+/// Page-aligned memory the application allocates for `V4L2_MEMORY_USERPTR` capture. The
+/// image starts on a page boundary, and the bytes in front of it are left free for a protocol
+/// header, so a captured frame can be sent as one message without copying the image.
+pub struct UserBuffer {
+    base: NonNull<u8>,
+    layout: Layout,
+    /// Offset of the image from `base`; also the room available for a header.
+    image_offset: usize,
+    /// Bytes of image the driver filled in the last capture.
+    bytes_used: usize,
+}
+
+impl UserBuffer {
+    /// Allocates room for `header_room` bytes of header in front of `image_len` bytes of image.
+    pub fn new(header_room: usize, image_len: usize) -> Self {
+        let page = page_size();
+        let image_offset = header_room.div_ceil(page) * page;
+        let size = image_offset + image_len.div_ceil(page) * page;
+        let layout = Layout::from_size_align(size.max(page), page).expect("Invalid user buffer layout");
+        let base = NonNull::new(unsafe { alloc_zeroed(layout) }).expect("Failed to allocate user buffer");
+        UserBuffer { base, layout, image_offset, bytes_used: 0 }
+    }
+
+    /// Space for the image, in bytes. At least what was asked for, rounded up to whole pages.
+    pub fn capacity(&self) -> usize {
+        self.layout.size() - self.image_offset
+    }
+
+    pub fn image_ptr(&mut self) -> NonNull<u8> {
+        unsafe { NonNull::new_unchecked(self.base.as_ptr().add(self.image_offset)) }
+    }
+
+    pub fn set_bytes_used(&mut self, bytes_used: usize) {
+        self.bytes_used = bytes_used.min(self.capacity());
+    }
+
+    /// The image the driver captured.
+    pub fn image(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.base.as_ptr().add(self.image_offset), self.bytes_used) }
+    }
+
+    /// Copies `prefix` into the room in front of the image and returns the prefix and image as
+    /// one contiguous slice, or `None` if the prefix does not fit.
+    pub fn with_prefix(&mut self, prefix: &[u8]) -> Option<&[u8]> {
+        let start = self.image_offset.checked_sub(prefix.len())?;
+        let whole = unsafe { std::slice::from_raw_parts_mut(self.base.as_ptr(), self.image_offset + self.bytes_used) };
+        whole[start..self.image_offset].copy_from_slice(prefix);
+        Some(&whole[start..])
+    }
+}
+
+impl Drop for UserBuffer {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.base.as_ptr(), self.layout) };
+    }
+}
+
+// A user buffer owns its allocation outright.
+unsafe impl Send for UserBuffer {}
+
+fn page_size() -> usize {
+    match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+        size if size > 0 => size as usize,
+        _ => 4096,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn image_is_page_aligned_with_room_in_front() {
+        let mut buffer = UserBuffer::new(100, 5000);
+        let page = page_size();
+        assert_eq!(buffer.image_ptr().as_ptr() as usize % page, 0);
+        assert!(buffer.capacity() >= 5000);
+        assert_eq!(buffer.capacity() % page, 0);
+
+        unsafe { buffer.image_ptr().as_ptr().write_bytes(7, 3) };
+        buffer.set_bytes_used(3);
+        assert_eq!(buffer.image(), &[7, 7, 7]);
+        assert_eq!(buffer.with_prefix(b"head").unwrap(), b"head\x07\x07\x07");
+        assert!(buffer.with_prefix(&vec![0; page + 1]).is_none());
+    }
 }
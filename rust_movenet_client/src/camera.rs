@@ -1,162 +1,933 @@
-use crate::ioctl_macros::*;
-use crate::buffer::Buffer;
-use std::fs::{OpenOptions, File};
+use crate::buffer::{Buffer, Ownership, UserBuffer};
+use crate::controls::{self, ControlInfo};
+use crate::device::VideoDevice;
+use crate::discovery::Capabilities;
+use crate::format::{self, FourCc, FormatDescription, FrameInterval, FrameIntervals, FrameSizes, PixFormat, Rect};
+use std::fs::OpenOptions;
 use std::io::{Error, ErrorKind};
 use std::num::NonZeroUsize;
+use std::os::fd::{AsFd, BorrowedFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::prelude::AsRawFd;
-use nix::sys::mman::{mmap, munmap, MapFlags, ProtFlags};
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
 use std::ptr::NonNull;
 use std::mem::zeroed;
+use std::time::{Duration, Instant};
 use v4l2_sys_mit::*;
 
-const V4L2_CAP_VIDEO_CAPTURE: u32 = 1 << 0;
+/// A dequeued capture buffer. The driver cannot reuse the buffer until the frame is dropped,
+/// which hands it back with `VIDIOC_QBUF`. Frames borrow the camera, so it cannot stop
+/// streaming or unmap buffers while any of them are still alive.
+///
+/// Other frame sources hand out detached frames, which borrow their own buffers instead.
+pub struct Frame<'a> {
+    /// The camera to hand the buffer back to, or `None` for frames from another source.
+    camera: Option<&'a Camera>,
+    index: u32,
+    /// The captured bytes of each memory plane, e.g. Y and then UV for NV12M. Formats in a
+    /// single buffer, including NV12, have one plane holding the whole image.
+    pub planes: Vec<&'a [u8]>,
+    pub info: FrameInfo,
+}
+
+impl<'a> Frame<'a> {
+    /// A frame that is not in a camera buffer, such as one read from a file.
+    pub fn detached(planes: Vec<&'a [u8]>, info: FrameInfo) -> Self {
+        Frame { camera: None, index: 0, planes, info }
+    }
+}
+
+/// What the driver reported about a captured frame besides its data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameInfo {
+    /// The driver's frame counter. It starts at 0 when streaming starts and skips the frames
+    /// the driver dropped, see [`SequenceGaps`].
+    pub sequence: u32,
+    /// `V4L2_BUF_FLAG_*` bits, such as `V4L2_BUF_FLAG_ERROR` for data that may be corrupt.
+    pub flags: u32,
+    /// Capture time on the CLOCK_MONOTONIC clock, in microseconds; see [`monotonic_micros`].
+    pub timestamp_us: u64,
+}
+
+impl FrameInfo {
+    /// Reads a dequeued buffer. Drivers that stamp buffers with another clock, or not at all,
+    /// get the time of the dequeue instead, so timestamps can always be compared with
+    /// [`monotonic_micros`].
+    fn from_buffer(buffer: &v4l2_buffer) -> Self {
+        let timestamp_us = if buffer.flags & V4L2_BUF_FLAG_TIMESTAMP_MASK == V4L2_BUF_FLAG_TIMESTAMP_MONOTONIC {
+            buffer.timestamp.tv_sec as u64 * 1_000_000 + buffer.timestamp.tv_usec as u64
+        } else {
+            monotonic_micros()
+        };
+        FrameInfo { sequence: buffer.sequence, flags: buffer.flags, timestamp_us }
+    }
+
+    /// Whether the driver flagged the data as possibly corrupt, e.g. after a transfer error.
+    pub fn is_corrupt(&self) -> bool {
+        self.flags & V4L2_BUF_FLAG_ERROR != 0
+    }
+}
+
+/// Counts the frames a driver dropped, from the gaps in the sequence numbers of the frames it
+/// delivered. Frames dequeued and then skipped by the client are not gaps.
+#[derive(Debug, Default)]
+pub struct SequenceGaps {
+    last: Option<u32>,
+}
+
+impl SequenceGaps {
+    /// Notes the next delivered frame and returns how many frames are missing before it. A
+    /// sequence number that does not go up means streaming restarted, as it does when a lost
+    /// camera is reopened, so it is no gap.
+    pub fn record(&mut self, sequence: u32) -> u32 {
+        let missed = match self.last {
+            Some(last) if sequence > last => sequence - last - 1,
+            _ => 0,
+        };
+        self.last = Some(sequence);
+        missed
+    }
+}
+
+impl Drop for Frame<'_> {
+    fn drop(&mut self) {
+        let Some(camera) = self.camera else { return };
+        if let Err(e) = camera.requeue(self.index) {
+            eprintln!("Failed to requeue buffer {}: {}", self.index, e);
+        }
+    }
+}
+
+/// How capture buffers are allocated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Memory {
+    /// The driver allocates buffers and the client maps them (`V4L2_MEMORY_MMAP`).
+    Mmap,
+    /// The client allocates page-aligned buffers with `header_room` free bytes in front of
+    /// each image and the driver fills them (`V4L2_MEMORY_USERPTR`).
+    UserPtr { header_room: usize },
+}
+
+/// What to ask the driver for when opening a [`Camera`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CameraOptions {
+    /// Number of buffers to ask for whenever buffers are (re)allocated.
+    pub buffer_count: u32,
+    pub width: u32,
+    pub height: u32,
+    pub fourcc: u32,
+    /// How long [`Camera::get_frame`] waits before giving up. `None` opens the device
+    /// blocking, and waits forever.
+    pub timeout: Option<Duration>,
+    pub memory: Memory,
+}
+
+/// Where a [`Camera`] is in its setup. Teardown walks back down the same steps, and every
+/// transition is a no-op when the camera is already in the target state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CameraState {
+    /// No buffers are allocated.
+    Idle,
+    /// Buffers are allocated and mapped but the driver is not capturing into them.
+    BuffersMapped,
+    /// Every buffer was queued and the driver is capturing.
+    Streaming,
+}
 
 pub struct Camera {
-    pub media_fd: File,
-    pub buffers: Vec<Buffer>,
+    pub device: Box<dyn VideoDevice>,
+    /// Capture buffers by index, each split into its memory planes.
+    pub buffers: Vec<Vec<Buffer>>,
     pub reqbufs: v4l2_requestbuffers,
+    /// The format the driver applied, which may differ from the one requested.
+    pub format: PixFormat,
+    capabilities: Capabilities,
+    options: CameraOptions,
+    state: CameraState,
+    /// Whether buffers are exported as DMABUFs whenever they are mapped.
+    export_dmabuf: bool,
 }
 
 impl Camera {
-    pub fn new(device_path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+    /// Opens `device_path` and asks for the format in `options`. Check [`Camera::format`] for
+    /// what the driver actually picked. With a timeout the device is opened non-blocking and
+    /// [`Camera::get_frame`] fails with [`ErrorKind::TimedOut`] when no frame arrives in time.
+    pub fn new(device_path: &str, options: &CameraOptions) -> Result<Self, Box<dyn std::error::Error>> {
         let media_fd = OpenOptions::new()
             .read(true)
             .write(true)
+            .custom_flags(if options.timeout.is_some() { libc::O_NONBLOCK } else { 0 })
             .open(device_path)?;
 
         println!("Camera device fd: {}", media_fd.as_raw_fd());
+        Camera::with_device(device_path, Box::new(media_fd), options)
+    }
 
-        let mut capabilities = v4l2_capability { ..unsafe { zeroed() } };
-        if unsafe { query_capabilities(media_fd.as_raw_fd(), &mut capabilities).is_err() } {
-            return Err(Box::new(Error::last_os_error()));
-        }
+    /// Sets up a camera on an already open `device`, which is called `name` in errors. A
+    /// non-blocking device needs a timeout in `options`.
+    pub fn with_device(name: &str, device: Box<dyn VideoDevice>, options: &CameraOptions) -> Result<Self, Box<dyn std::error::Error>> {
+        let capabilities = Capabilities::query(&*device)?;
+        let buf_type = capabilities
+            .capture_buf_type()
+            .ok_or_else(|| Error::other(format!("{} does not support video capture", name)))?;
 
-        if capabilities.capabilities & V4L2_CAP_VIDEO_CAPTURE == 0 {
-            return Err(Box::new(Error::new(ErrorKind::Other, "Device does not support video capture")));
+        // The format has to be set before buffers are requested, since it decides their size.
+        let format = format::set_pix_format(&*device, buf_type, options.width, options.height, options.fourcc)?;
+        println!("Capture format: {}", format);
+        check_stride(&format)?;
+        if format.num_planes > 1 && options.memory != Memory::Mmap {
+            return Err(Box::new(Error::other(format!("USERPTR capture needs a single-buffer format, not {}", FourCc(format.fourcc)))));
         }
 
-        let mut reqbufs = v4l2_requestbuffers {
-            count: 20,
-            type_: v4l2_buf_type_V4L2_BUF_TYPE_VIDEO_CAPTURE,
-            memory: v4l2_memory_V4L2_MEMORY_MMAP,
+        let reqbufs = v4l2_requestbuffers {
+            count: 0,
+            type_: buf_type,
+            memory: match options.memory {
+                Memory::Mmap => v4l2_memory_V4L2_MEMORY_MMAP,
+                Memory::UserPtr { .. } => v4l2_memory_V4L2_MEMORY_USERPTR,
+            },
             capabilities: 0,
             flags: 0,
             reserved: [0; 3],
         };
+        let mut camera = Camera {
+            device,
+            buffers: Vec::new(),
+            reqbufs,
+            format,
+            capabilities,
+            options: *options,
+            state: CameraState::Idle,
+            export_dmabuf: false,
+        };
+        camera.map_buffers()?;
+        Ok(camera)
+    }
 
-        if unsafe { request_buffers(media_fd.as_raw_fd(), &mut reqbufs).is_err() } {
-            return Err(Box::new(Error::last_os_error()));
+    /// Allocates and maps the capture buffers, or allocates user buffers in USERPTR mode.
+    /// Does nothing unless the camera is idle.
+    pub fn map_buffers(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.state != CameraState::Idle {
+            return Ok(());
         }
 
-        let mut buffers: Vec<Buffer> = Vec::with_capacity(reqbufs.count as usize);
-        for i in 0..reqbufs.count {
-            let mut buffer_info = v4l2_buffer {
-                index: i,
-                type_: reqbufs.type_,
-                memory: v4l2_memory_V4L2_MEMORY_MMAP,
-                ..unsafe { zeroed() }
-            };
+        let mut reqbufs = v4l2_requestbuffers { count: self.options.buffer_count, ..self.reqbufs };
+        self.device.request_buffers(&mut reqbufs).map_err(Error::from)?;
+        self.reqbufs = reqbufs;
 
-            if unsafe { query_buffers(media_fd.as_raw_fd(), &mut buffer_info).is_err() } {
-                return Err(Box::new(Error::last_os_error()));
+        // Anything mapped before a failure is unmapped again, and the buffers freed.
+        let allocated = match self.options.memory {
+            Memory::Mmap => self.mmap_buffers().and_then(|()| self.export_dmabufs()),
+            Memory::UserPtr { header_room } => {
+                self.allocate_user_buffers(header_room);
+                Ok(())
             }
+        };
+        if let Err(e) = allocated {
+            self.release_buffers().ok();
+            return Err(e);
+        }
+        self.state = CameraState::BuffersMapped;
+        Ok(())
+    }
+
+    fn mmap_buffers(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.buffers.reserve(self.reqbufs.count as usize);
+        for i in 0..self.reqbufs.count {
+            let mut plane_info = [unsafe { zeroed::<v4l2_plane>() }; VIDEO_MAX_PLANES as usize];
+            let mut buffer_info = self.buffer_info(i, &mut plane_info);
 
-            let buffer_length = buffer_info.length;
-            let non_zero_length = NonZeroUsize::new(buffer_length as usize)
-                .ok_or_else(|| Error::new(ErrorKind::Other, "Invalid buffer length"))?;
-
-            let buffer_start = unsafe {
-                mmap::<&File>(
-                    None,
-                    non_zero_length,
-                    ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
-                    MapFlags::MAP_SHARED,
-                    &media_fd,
-                    buffer_info.m.offset.into(),
-                )
-            };
-
-            match buffer_start {
-                Ok(start) => {
-                    buffers.push(Buffer {
-                        start: NonNull::new(start.as_ptr() as *mut u8).expect("Failed to create NonNull pointer"),
-                        length: buffer_length as usize,
-                    });
+            self.device.query_buffer(&mut buffer_info).map_err(Error::from)?;
+
+            // Push the buffer before mapping its planes, so a failure part way still unmaps
+            // the planes that were mapped.
+            self.buffers.push(Vec::new());
+            if self.is_multiplanar() {
+                for plane in &plane_info[..buffer_info.length as usize] {
+                    let mapped = self.mmap_plane(plane.length, unsafe { plane.m.mem_offset })?;
+                    self.buffers[i as usize].push(mapped);
                 }
-                Err(e) => {
-                    eprintln!("mmap [FAILED]: {}", e);
-                    for mapped_buffer in &buffers {
-                        unsafe {
-                            munmap(mapped_buffer.start.cast::<libc::c_void>(), mapped_buffer.length).ok();
-                        }
-                    }
-                    return Err(Box::new(Error::last_os_error()));
+            } else {
+                let mapped = self.mmap_plane(buffer_info.length, unsafe { buffer_info.m.offset })?;
+                self.buffers[i as usize].push(mapped);
+            }
+        }
+        Ok(())
+    }
+
+    fn mmap_plane(&self, length: u32, offset: u32) -> Result<Buffer, Box<dyn std::error::Error>> {
+        let non_zero_length = NonZeroUsize::new(length as usize)
+            .ok_or_else(|| Error::other("Invalid buffer length"))?;
+
+        match self.device.map(non_zero_length, offset) {
+            Ok(start) => Ok(Buffer {
+                start: NonNull::new(start.as_ptr() as *mut u8).expect("Failed to create NonNull pointer"),
+                length: length as usize,
+                dmabuf: None,
+                ownership: Ownership::Mapped,
+            }),
+            Err(e) => {
+                eprintln!("mmap [FAILED]: {}", e);
+                Err(Box::new(Error::from(e)))
+            }
+        }
+    }
+
+    fn allocate_user_buffers(&mut self, header_room: usize) {
+        let image_len = self.format.size_image as usize;
+        self.buffers = (0..self.reqbufs.count).map(|_| vec![Buffer::user(UserBuffer::new(header_room, image_len))]).collect();
+    }
+
+    /// Exports every plane of every capture buffer as a DMABUF fd (see [`Buffer::dmabuf`]) so
+    /// other devices or processes can read frames without a copy. The buffers stay mapped, and
+    /// are exported again whenever they are reallocated.
+    pub fn export_buffers(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.options.memory != Memory::Mmap {
+            return Err(Box::new(Error::other("Only MMAP capture buffers can be exported as DMABUFs")));
+        }
+        self.export_dmabuf = true;
+        self.export_dmabufs()
+    }
+
+    fn export_dmabufs(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.export_dmabuf {
+            return Ok(());
+        }
+        for (index, planes) in self.buffers.iter_mut().enumerate() {
+            for (plane, buffer) in planes.iter_mut().enumerate() {
+                if buffer.dmabuf.is_some() {
+                    continue;
                 }
+                let mut export = v4l2_exportbuffer {
+                    type_: self.reqbufs.type_,
+                    index: index as u32,
+                    plane: plane as u32,
+                    flags: (libc::O_RDONLY | libc::O_CLOEXEC) as u32,
+                    ..unsafe { zeroed() }
+                };
+                self.device.export_buffer(&mut export).map_err(Error::from)?;
+                // The driver handed us a new fd that nothing else owns.
+                buffer.dmabuf = Some(unsafe { OwnedFd::from_raw_fd(export.fd) });
             }
         }
+        Ok(())
+    }
+
+    /// Stops streaming if needed, unmaps or frees every buffer and releases them with a
+    /// zero-count `VIDIOC_REQBUFS`. Safe to call in any state and any number of times. If
+    /// streaming cannot be stopped, user buffers are leaked and the request left standing.
+    pub fn release_buffers(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let stopped = self.stop_streaming();
+
+        for buffer in self.buffers.drain(..).flatten() {
+            match buffer.ownership {
+                // Without a successful STREAMOFF the driver may still be writing into user
+                // memory, so it is leaked rather than freed under it.
+                Ownership::User(_) if stopped.is_err() => std::mem::forget(buffer),
+                _ => buffer.release(),
+            }
+        }
+
+        // Nor are the buffers given back while the driver may still be using them.
+        if self.reqbufs.count > 0 && stopped.is_ok() {
+            let mut reqbufs = v4l2_requestbuffers { count: 0, ..self.reqbufs };
+            self.device.request_buffers(&mut reqbufs).map_err(Error::from)?;
+            self.reqbufs.count = 0;
+        }
+
+        self.state = CameraState::Idle;
+        stopped
+    }
+
+    pub fn format(&self) -> PixFormat {
+        self.format
+    }
+
+    /// What the device reported when it was opened, including the bus it is plugged into.
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
+    }
+
+    pub fn formats(&self) -> Result<Vec<FormatDescription>, Box<dyn std::error::Error>> {
+        format::enumerate_formats(&*self.device, self.reqbufs.type_)
+    }
+
+    pub fn frame_sizes(&self, fourcc: u32) -> Result<FrameSizes, Box<dyn std::error::Error>> {
+        format::enumerate_frame_sizes(&*self.device, fourcc)
+    }
+
+    /// Frame intervals the device supports for the applied format and size.
+    pub fn frame_intervals(&self) -> Result<FrameIntervals, Box<dyn std::error::Error>> {
+        format::enumerate_frame_intervals(&*self.device, self.format.fourcc, self.format.width, self.format.height)
+    }
+
+    /// The current frame interval, or `None` if the driver picks the rate itself.
+    pub fn frame_interval(&self) -> Result<Option<FrameInterval>, Box<dyn std::error::Error>> {
+        format::get_frame_interval(&*self.device, self.reqbufs.type_)
+    }
+
+    /// Requests `fps` frames per second and returns the interval the driver applied.
+    pub fn set_frame_rate(&mut self, fps: u32) -> Result<FrameInterval, Box<dyn std::error::Error>> {
+        format::set_frame_interval(&*self.device, self.reqbufs.type_, FrameInterval::from_fps(fps))
+    }
+
+    /// The area of the sensor [`Camera::set_crop`] can pick from.
+    pub fn crop_bounds(&self) -> Result<Rect, Box<dyn std::error::Error>> {
+        format::get_selection(&*self.device, self.reqbufs.type_, V4L2_SEL_TGT_CROP_BOUNDS)
+    }
+
+    /// Captures only `rect` of the sensor and returns the crop the driver applied. Without a
+    /// scaler the frames shrink to the crop, which drivers refuse while buffers are allocated,
+    /// so the buffers are released first and mapped again when streaming starts (or by
+    /// [`Camera::map_buffers`]); [`Camera::format`] is updated to the new frame size. Fails
+    /// while streaming, since most drivers cannot change the frame size then.
+    pub fn set_crop(&mut self, rect: Rect) -> Result<Rect, Box<dyn std::error::Error>> {
+        if self.state == CameraState::Streaming {
+            return Err(Box::new(Error::new(ErrorKind::ResourceBusy, "Cannot crop while streaming")));
+        }
+
+        self.release_buffers()?;
+        let applied = format::set_selection(&*self.device, self.reqbufs.type_, V4L2_SEL_TGT_CROP, rect)?;
+        let format = format::get_pix_format(&*self.device, self.reqbufs.type_)?;
+        if format != self.format {
+            println!("Capture format: {}", format);
+            check_stride(&format)?;
+            self.format = format;
+        }
+        Ok(applied)
+    }
+
+    pub fn controls(&self) -> Result<Vec<ControlInfo>, Box<dyn std::error::Error>> {
+        controls::enumerate_controls(&*self.device)
+    }
 
-        Ok(Camera { media_fd, buffers, reqbufs })
+    pub fn control(&self, control: &ControlInfo) -> Result<i64, Box<dyn std::error::Error>> {
+        controls::get_control(&*self.device, control)
     }
 
+    /// Writes `values` in order in a single request where the driver supports it.
+    pub fn set_controls(&mut self, values: &[(&ControlInfo, i64)]) -> Result<(), Box<dyn std::error::Error>> {
+        controls::set_controls(&*self.device, values)
+    }
+
+    /// Queues every buffer and starts capturing, mapping buffers first if they were released.
+    /// Does nothing if the camera is already streaming.
     pub fn start_streaming(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        match self.state {
+            CameraState::Streaming => return Ok(()),
+            CameraState::Idle => self.map_buffers()?,
+            CameraState::BuffersMapped => {}
+        }
+
         for i in 0..self.reqbufs.count {
-            let mut buffer_info = v4l2_buffer {
-                index: i,
-                type_: self.reqbufs.type_,
-                memory: self.reqbufs.memory,
-                ..unsafe { zeroed() }
-            };
-            if unsafe { q_buffer(self.media_fd.as_raw_fd(), &mut buffer_info).is_err() } {
-                return Err(Box::new(Error::last_os_error()));
+            if let Err(e) = self.requeue(i) {
+                // STREAMOFF takes back whatever was already queued.
+                self.stream_off().ok();
+                return Err(Box::new(e));
             }
         }
 
-        let buf_type = v4l2_buf_type_V4L2_BUF_TYPE_VIDEO_CAPTURE;
-        if unsafe { vidioc_streamon(self.media_fd.as_raw_fd(), &buf_type as *const _ as *const i32).is_err() } {
-            return Err(Box::new(Error::last_os_error()));
+        if let Err(e) = self.stream_on() {
+            self.stream_off().ok();
+            return Err(Box::new(e));
         }
 
+        self.state = CameraState::Streaming;
         println!("Streaming started!");
         Ok(())
     }
 
+    /// Stops capturing. The buffers stay mapped so streaming can be restarted without
+    /// reallocating them. Does nothing unless the camera is streaming. If STREAMOFF fails the
+    /// camera still counts as streaming, since the driver may hold on to the buffers, and
+    /// [`Camera::release_buffers`] tries again.
     pub fn stop_streaming(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let buf_type = v4l2_buf_type_V4L2_BUF_TYPE_VIDEO_CAPTURE;
-        if unsafe { vidioc_streamoff(self.media_fd.as_raw_fd(), &buf_type as *const _ as *const i32).is_err() } {
-            eprintln!("Failed to stop streaming: {}", Error::last_os_error());
+        if self.state != CameraState::Streaming {
+            return Ok(());
         }
 
-        for mapped_buffer in &self.buffers {
-            unsafe {
-                munmap(mapped_buffer.start.cast::<libc::c_void>(), mapped_buffer.length).ok();
+        self.stream_off()?;
+        self.state = CameraState::BuffersMapped;
+        Ok(())
+    }
+
+    fn stream_on(&self) -> std::io::Result<()> {
+        Ok(self.device.stream_on(self.reqbufs.type_)?)
+    }
+
+    fn stream_off(&self) -> std::io::Result<()> {
+        Ok(self.device.stream_off(self.reqbufs.type_)?)
+    }
+
+    /// Dequeues the next filled buffer, waiting up to the camera's timeout (or forever without
+    /// one) for it. Several frames can be held at once; each keeps its buffer out of the
+    /// driver's queue until it is dropped, so holding all of them stalls capture.
+    pub fn get_frame(&self) -> Result<Frame<'_>, Box<dyn std::error::Error>> {
+        let deadline = self.options.timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            if let Some(frame) = self.try_get_frame()? {
+                return Ok(frame);
+            }
+            // Only a non-blocking camera gets here, so there is always a deadline.
+            let Some(deadline) = deadline else { continue };
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() || !self.wait_readable(remaining)? {
+                let waited = self.options.timeout.unwrap_or_default().as_millis();
+                return Err(Box::new(Error::new(ErrorKind::TimedOut, format!("No frame from the camera in {} ms", waited))));
             }
         }
+    }
 
-        Ok(())
+    /// Dequeues the next frame of a USERPTR camera and swaps the buffer it was captured into
+    /// with `buffer`, which the driver gets to fill next. Unlike [`Camera::get_frame`] the image
+    /// is then owned, so it can be sent on from another thread without copying. Returns what
+    /// the driver reported about the frame. `buffer` is left as it was when no frame could be
+    /// dequeued, or when the driver refused it.
+    pub fn take_frame(&mut self, buffer: &mut UserBuffer) -> Result<FrameInfo, Box<dyn std::error::Error>> {
+        if !matches!(self.options.memory, Memory::UserPtr { .. }) {
+            return Err(Box::new(Error::other("Only USERPTR cameras hand out their buffers")));
+        }
+
+        let frame = self.get_frame()?;
+        let (index, bytes_used, info) = (frame.index, frame.planes[0].len(), frame.info);
+        // The buffer is requeued below with the spare in it, not by the frame guard.
+        std::mem::forget(frame);
+
+        self.swap_user_buffer(index, buffer);
+        if let Err(e) = self.requeue(index) {
+            // Swap back, so the captured buffer stays in its slot and the spare with the caller.
+            self.swap_user_buffer(index, buffer);
+            return Err(Box::new(e));
+        }
+        buffer.set_bytes_used(bytes_used);
+        Ok(info)
+    }
+
+    /// Puts `buffer` in slot `index` of a USERPTR camera and hands back the one that was there.
+    fn swap_user_buffer(&mut self, index: u32, buffer: &mut UserBuffer) {
+        // USERPTR cameras only use single-buffer formats, so every buffer has one plane.
+        let slot = &mut self.buffers[index as usize][0];
+        let Ownership::User(current) = &mut slot.ownership else {
+            unreachable!("USERPTR cameras only hold user buffers");
+        };
+        std::mem::swap(current, buffer);
+        slot.start = current.image_ptr();
+        slot.length = current.capacity();
+    }
+
+    /// Dequeues a filled buffer if one is ready. On a non-blocking camera this returns
+    /// `Ok(None)` instead of waiting, which suits an event loop polling [`Camera::as_fd`].
+    pub fn try_get_frame(&self) -> Result<Option<Frame<'_>>, Box<dyn std::error::Error>> {
+        let mut plane_info = [unsafe { zeroed::<v4l2_plane>() }; VIDEO_MAX_PLANES as usize];
+        let mut buffer_info = self.buffer_info(0, &mut plane_info);
+
+        match self.device.dequeue_buffer(&mut buffer_info) {
+            Ok(_) => {}
+            Err(Errno::EAGAIN) => return Ok(None),
+            Err(e) => return Err(Box::new(Error::from(e))),
+        }
+
+        let buffer = &self.buffers[buffer_info.index as usize];
+        let planes = if self.is_multiplanar() {
+            // Data in a plane may start after a driver-specific header.
+            buffer
+                .iter()
+                .zip(&plane_info)
+                .map(|(plane, info)| {
+                    let offset = info.data_offset.min(info.bytesused) as usize;
+                    unsafe { std::slice::from_raw_parts(plane.start.as_ptr().add(offset), info.bytesused as usize - offset) }
+                })
+                .collect()
+        } else {
+            vec![unsafe { std::slice::from_raw_parts(buffer[0].start.as_ptr(), buffer_info.bytesused as usize) }]
+        };
+
+        let info = FrameInfo::from_buffer(&buffer_info);
+        Ok(Some(Frame { camera: Some(self), index: buffer_info.index, planes, info }))
+    }
+
+    /// Waits until a filled buffer can be dequeued. Returns `false` if `timeout` ran out first.
+    pub fn wait_readable(&self, timeout: Duration) -> std::io::Result<bool> {
+        let timeout = PollTimeout::try_from(timeout).unwrap_or(PollTimeout::MAX);
+        let mut fds = [PollFd::new(self.device.as_fd(), PollFlags::POLLIN)];
+        loop {
+            match poll(&mut fds, timeout) {
+                Ok(ready) => return Ok(ready > 0),
+                Err(Errno::EINTR) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    fn requeue(&self, index: u32) -> std::io::Result<()> {
+        let mut plane_info = [unsafe { zeroed::<v4l2_plane>() }; VIDEO_MAX_PLANES as usize];
+        let user_buffer = self
+            .buffers
+            .get(index as usize)
+            .and_then(|planes| planes.first())
+            .filter(|buffer| matches!(buffer.ownership, Ownership::User(_)));
+        if let Some(buffer) = user_buffer {
+            plane_info[0].m.userptr = buffer.start.as_ptr() as libc::c_ulong;
+            plane_info[0].length = buffer.length as u32;
+        }
+
+        let mut buffer_info = self.buffer_info(index, &mut plane_info);
+        if let (Some(buffer), false) = (user_buffer, self.is_multiplanar()) {
+            buffer_info.m.userptr = buffer.start.as_ptr() as libc::c_ulong;
+            buffer_info.length = buffer.length as u32;
+        }
+        Ok(self.device.queue_buffer(&mut buffer_info)?)
     }
 
-    pub fn get_frame(&mut self) -> Result<&[u8], Box<dyn std::error::Error>> {
+    fn is_multiplanar(&self) -> bool {
+        self.reqbufs.type_ == v4l2_buf_type_V4L2_BUF_TYPE_VIDEO_CAPTURE_MPLANE
+    }
+
+    /// A `v4l2_buffer` for `index`. Multi-planar ioctls read and fill in the per-plane details
+    /// through `planes`, which has to outlive the ioctl.
+    fn buffer_info(&self, index: u32, planes: &mut [v4l2_plane; VIDEO_MAX_PLANES as usize]) -> v4l2_buffer {
         let mut buffer_info = v4l2_buffer {
+            index,
             type_: self.reqbufs.type_,
             memory: self.reqbufs.memory,
             ..unsafe { zeroed() }
         };
-
-        if unsafe { dq_buffer(self.media_fd.as_raw_fd(), &mut buffer_info).is_err() } {
-            return Err(Box::new(Error::last_os_error()));
+        if self.is_multiplanar() {
+            buffer_info.m.planes = planes.as_mut_ptr();
+            // The number of entries in `planes`; the driver checks it covers the format's planes.
+            buffer_info.length = planes.len() as u32;
         }
+        buffer_info
+    }
+}
 
-        let frame_data = unsafe {
-            std::slice::from_raw_parts(
-                self.buffers[buffer_info.index as usize].start.as_ptr(),
-                buffer_info.bytesused as usize,
-            )
-        };
+/// The device fd, for registering the camera with `poll` or `epoll` next to other sockets.
+/// A readable camera has a filled buffer waiting for [`Camera::try_get_frame`].
+impl AsFd for Camera {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.device.as_fd()
+    }
+}
 
-        if unsafe { q_buffer(self.media_fd.as_raw_fd(), &mut buffer_info).is_err() } {
-            return Err(Box::new(Error::last_os_error()));
+impl Drop for Camera {
+    fn drop(&mut self) {
+        if let Err(e) = self.release_buffers() {
+            eprintln!("Failed to release camera buffers: {}", e);
         }
+    }
+}
+
+/// Refuses formats whose planes are padded differently, see [`PixFormat::uniform_stride`].
+fn check_stride(format: &PixFormat) -> std::io::Result<()> {
+    if format.uniform_stride {
+        return Ok(());
+    }
+    Err(Error::other(format!("The planes of {} have different strides, which frames cannot carry", format)))
+}
+
+/// Whether `error` means the device went away, e.g. because the camera was unplugged. The
+/// camera has to be opened again; this one will not deliver any more frames.
+pub fn is_device_lost(error: &(dyn std::error::Error + 'static)) -> bool {
+    error
+        .downcast_ref::<Error>()
+        .and_then(Error::raw_os_error)
+        .is_some_and(|errno| errno == libc::ENODEV || errno == libc::EIO)
+}
+
+/// Whether `error` is the timeout [`Camera::get_frame`] returns when no frame arrived in time.
+pub fn is_timeout(error: &(dyn std::error::Error + 'static)) -> bool {
+    error.downcast_ref::<Error>().is_some_and(|e| e.kind() == ErrorKind::TimedOut)
+}
+
+/// Current CLOCK_MONOTONIC time in microseconds, comparable with [`FrameInfo::timestamp_us`].
+pub fn monotonic_micros() -> u64 {
+    let mut ts = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut ts) };
+    ts.tv_sec as u64 * 1_000_000 + ts.tv_nsec as u64 / 1_000
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::{FakeDevice, FakeLog};
+    use crate::format::V4L2_PIX_FMT_YUYV;
+    use std::sync::{Arc, Mutex};
+
+    const OPTIONS: CameraOptions = CameraOptions {
+        buffer_count: 4,
+        width: 640,
+        height: 480,
+        fourcc: V4L2_PIX_FMT_YUYV,
+        timeout: None,
+        memory: Memory::Mmap,
+    };
+    const FRAME_LEN: u32 = 640 * 480 * 2;
+
+    type FakeLogHandle = Arc<Mutex<FakeLog>>;
+
+    /// Opens a camera on `device`, returning it along with the device's log.
+    fn open(device: FakeDevice) -> (Result<Camera, Box<dyn std::error::Error>>, FakeLogHandle) {
+        let log = device.log();
+        (Camera::with_device("fake", Box::new(device), &OPTIONS), log)
+    }
+
+    /// A buffer for the fake to deliver: `index` filled with `bytes_used` bytes of frame
+    /// `sequence`, stamped `sequence` milliseconds after the monotonic clock's first second.
+    fn filled(index: u32, bytes_used: u32, sequence: u32) -> v4l2_buffer {
+        let mut buffer: v4l2_buffer = unsafe { zeroed() };
+        buffer.index = index;
+        buffer.bytesused = bytes_used;
+        buffer.sequence = sequence;
+        buffer.timestamp.tv_sec = 1;
+        buffer.timestamp.tv_usec = sequence as i64 * 1_000;
+        buffer.flags = V4L2_BUF_FLAG_TIMESTAMP_MONOTONIC;
+        buffer
+    }
+
+    /// Whether the fake's mapping at `start` is still there; `msync` fails with ENOMEM on
+    /// memory that is not mapped.
+    fn is_mapped(&(start, length): &(usize, usize)) -> bool {
+        unsafe { libc::msync(start as *mut libc::c_void, length, libc::MS_ASYNC) == 0 }
+    }
+
+    #[test]
+    fn buffers_are_mapped_and_released() {
+        let (camera, log) = open(FakeDevice::new(FRAME_LEN));
+        let mut camera = camera.unwrap();
+        assert_eq!((camera.format().width, camera.format().size_image), (640, FRAME_LEN));
+        assert_eq!(camera.buffers.len(), 4);
+        assert!(log.lock().unwrap().mappings.iter().all(is_mapped));
+
+        camera.start_streaming().unwrap();
+        drop(camera);
+        let log = log.lock().unwrap();
+        assert_eq!(
+            log.calls[log.calls.len() - 7..],
+            ["VIDIOC_QBUF 0", "VIDIOC_QBUF 1", "VIDIOC_QBUF 2", "VIDIOC_QBUF 3", "VIDIOC_STREAMON", "VIDIOC_STREAMOFF", "VIDIOC_REQBUFS 0"]
+        );
+        assert_eq!(log.mappings.len(), 4);
+        assert!(!log.mappings.iter().any(is_mapped));
+    }
+
+    #[test]
+    fn streaming_restarts_on_the_same_buffers() {
+        let (camera, log) = open(FakeDevice::new(FRAME_LEN));
+        let mut camera = camera.unwrap();
+        let opened = log.lock().unwrap().calls.len();
+
+        camera.start_streaming().unwrap();
+        camera.stop_streaming().unwrap();
+        // Stopping again is a no-op, and the buffers stay mapped for the restart.
+        camera.stop_streaming().unwrap();
+        assert!(log.lock().unwrap().mappings.iter().all(is_mapped));
+        camera.start_streaming().unwrap();
+        drop(camera);
+
+        let log = log.lock().unwrap();
+        let queue_all = ["VIDIOC_QBUF 0", "VIDIOC_QBUF 1", "VIDIOC_QBUF 2", "VIDIOC_QBUF 3", "VIDIOC_STREAMON", "VIDIOC_STREAMOFF"];
+        let expected: Vec<&str> = queue_all.iter().chain(&queue_all).copied().chain(["VIDIOC_REQBUFS 0"]).collect();
+        assert_eq!(log.calls[opened..], expected);
+        // Mapped once when the camera was opened, and unmapped once when it was dropped.
+        assert_eq!(log.calls.iter().filter(|call| call.starts_with("mmap")).count(), 4);
+        assert_eq!(log.mappings.len(), 4);
+        assert!(!log.mappings.iter().any(is_mapped));
+    }
+
+    #[test]
+    fn user_buffers_are_kept_when_streaming_cannot_be_stopped() {
+        let device = FakeDevice::new(FRAME_LEN).fail("VIDIOC_STREAMOFF", 0, Errno::EIO);
+        let log = device.log();
+        let options = CameraOptions { memory: Memory::UserPtr { header_room: 0 }, ..OPTIONS };
+        let mut camera = Camera::with_device("fake", Box::new(device), &options).unwrap();
+
+        camera.start_streaming().unwrap();
+        assert!(camera.release_buffers().is_err());
+        assert!(camera.buffers.is_empty());
+        assert_eq!(log.lock().unwrap().calls.last().unwrap(), "VIDIOC_STREAMOFF");
+    }
+
+    #[test]
+    fn failed_stop_is_retried_before_the_buffers_are_released() {
+        let device = FakeDevice::new(FRAME_LEN).fail("VIDIOC_STREAMOFF", 0, Errno::EIO);
+        let log = device.log();
+        let options = CameraOptions { memory: Memory::UserPtr { header_room: 0 }, ..OPTIONS };
+        let mut camera = Camera::with_device("fake", Box::new(device), &options).unwrap();
+
+        camera.start_streaming().unwrap();
+        assert!(camera.stop_streaming().is_err());
+        camera.release_buffers().unwrap();
+        let log = log.lock().unwrap();
+        assert_eq!(log.calls[log.calls.len() - 3..], ["VIDIOC_STREAMOFF", "VIDIOC_STREAMOFF", "VIDIOC_REQBUFS 0"]);
+    }
+
+    #[test]
+    fn devices_that_cannot_capture_are_rejected() {
+        let (camera, log) = open(FakeDevice::new(FRAME_LEN).with_caps(V4L2_CAP_META_CAPTURE | V4L2_CAP_STREAMING));
+        assert_eq!(camera.err().unwrap().to_string(), "fake does not support video capture");
+        // Nothing is set up, so there is nothing to undo.
+        assert_eq!(log.lock().unwrap().calls, ["VIDIOC_QUERYCAP"]);
+    }
+
+    #[test]
+    fn failed_buffer_request_is_reported() {
+        let (camera, log) = open(FakeDevice::new(FRAME_LEN).fail("VIDIOC_REQBUFS", 0, Errno::EBUSY));
+        assert_eq!(camera.err().unwrap().downcast_ref::<Error>().and_then(Error::raw_os_error), Some(libc::EBUSY));
+        assert_eq!(log.lock().unwrap().calls, ["VIDIOC_QUERYCAP", "VIDIOC_S_FMT 640x480", "VIDIOC_G_FMT", "VIDIOC_REQBUFS 4"]);
+    }
+
+    #[test]
+    fn zero_length_buffers_are_released_again() {
+        let (camera, log) = open(FakeDevice::new(0));
+        assert_eq!(camera.err().unwrap().to_string(), "Invalid buffer length");
+        let log = log.lock().unwrap();
+        assert!(log.mappings.is_empty());
+        assert_eq!(log.calls.last().unwrap(), "VIDIOC_REQBUFS 0");
+    }
+
+    #[test]
+    fn mmap_failure_unmaps_the_buffers_mapped_so_far() {
+        let (camera, log) = open(FakeDevice::new(FRAME_LEN).fail("mmap", 2, Errno::ENOMEM));
+        assert!(camera.is_err());
+        let log = log.lock().unwrap();
+        assert_eq!(log.mappings.len(), 2);
+        assert!(!log.mappings.iter().any(is_mapped));
+        // The third buffer starts two buffers in.
+        assert_eq!(log.calls[log.calls.len() - 2..], [format!("mmap {}", 2 * FRAME_LEN), "VIDIOC_REQBUFS 0".to_string()]);
+    }
+
+    #[test]
+    fn failed_query_releases_the_buffers() {
+        let (camera, log) = open(FakeDevice::new(FRAME_LEN).fail("VIDIOC_QUERYBUF", 1, Errno::EINVAL));
+        assert!(camera.is_err());
+        let log = log.lock().unwrap();
+        assert_eq!(log.mappings.len(), 1);
+        assert!(!is_mapped(&log.mappings[0]));
+        assert_eq!(log.calls.last().unwrap(), "VIDIOC_REQBUFS 0");
+    }
+
+    #[test]
+    fn cropping_shrinks_the_frame_and_releases_the_buffers() {
+        let (camera, log) = open(FakeDevice::new(FRAME_LEN));
+        let mut camera = camera.unwrap();
+        assert_eq!(camera.crop_bounds().unwrap(), Rect::full(1920, 1080));
+
+        // The fake rounds odd values down, like a sensor cropping in 2x2 blocks.
+        let applied = camera.set_crop(Rect { left: 101, top: 51, width: 321, height: 241 }).unwrap();
+        assert_eq!(applied, Rect { left: 100, top: 50, width: 320, height: 240 });
+        assert_eq!((camera.format().width, camera.format().height), (320, 240));
+        assert!(camera.buffers.is_empty());
+        assert!(!log.lock().unwrap().mappings.iter().any(is_mapped));
+
+        camera.start_streaming().unwrap();
+        assert_eq!(camera.buffers.len(), 4);
+        let error = camera.set_crop(Rect::full(640, 480)).unwrap_err();
+        assert_eq!(error.downcast_ref::<Error>().map(Error::kind), Some(ErrorKind::ResourceBusy));
+    }
+
+    #[test]
+    fn buffers_are_released_before_the_crop_changes_the_frame_size() {
+        // Like a driver without a scaler, the fake will not resize frames under allocated buffers.
+        let device = FakeDevice::new(FRAME_LEN);
+        let mut reqbufs = v4l2_requestbuffers { count: 4, ..unsafe { zeroed() } };
+        device.request_buffers(&mut reqbufs).unwrap();
+        let error = format::set_selection(&device, v4l2_buf_type_V4L2_BUF_TYPE_VIDEO_CAPTURE, V4L2_SEL_TGT_CROP, Rect::full(320, 240)).unwrap_err();
+        assert_eq!(error.downcast_ref::<Error>().and_then(Error::raw_os_error), Some(libc::EBUSY));
+
+        let (camera, log) = open(FakeDevice::new(FRAME_LEN));
+        camera.unwrap().set_crop(Rect::full(320, 240)).unwrap();
+        let log = log.lock().unwrap();
+        let crop = log.calls.iter().position(|call| call.starts_with("VIDIOC_S_SELECTION")).unwrap();
+        assert_eq!(log.calls[crop - 1..=crop], ["VIDIOC_REQBUFS 0", "VIDIOC_S_SELECTION 320x240+0+0"]);
+    }
+
+    #[test]
+    fn dropped_frames_are_queued_again() {
+        let (camera, log) = open(FakeDevice::new(FRAME_LEN).deliver(filled(2, 100, 0)));
+        let mut camera = camera.unwrap();
+        camera.start_streaming().unwrap();
+
+        let frame = camera.get_frame().unwrap();
+        assert_eq!((frame.index, frame.planes[0].len()), (2, 100));
+        assert_eq!(frame.planes[0].as_ptr(), camera.buffers[2][0].start.as_ptr().cast_const());
+        assert_eq!(log.lock().unwrap().calls.last().unwrap(), "VIDIOC_DQBUF");
+        drop(frame);
+        assert_eq!(log.lock().unwrap().calls.last().unwrap(), "VIDIOC_QBUF 2");
+    }
+
+    #[test]
+    fn frames_report_what_the_driver_stamped_on_them() {
+        let mut corrupt = filled(1, 100, 3);
+        corrupt.flags |= V4L2_BUF_FLAG_ERROR;
+        let (camera, _log) = open(FakeDevice::new(FRAME_LEN).deliver(filled(0, 100, 0)).deliver(corrupt));
+        let mut camera = camera.unwrap();
+        camera.start_streaming().unwrap();
+
+        let mut gaps = SequenceGaps::default();
+        let first = camera.get_frame().unwrap().info;
+        let second = camera.get_frame().unwrap().info;
+        assert_eq!((first.timestamp_us, first.is_corrupt()), (1_000_000, false));
+        assert_eq!((second.timestamp_us, second.is_corrupt()), (1_003_000, true));
+        // Frames 1 and 2 never arrived.
+        assert_eq!([gaps.record(first.sequence), gaps.record(second.sequence)], [0, 2]);
+    }
+
+    #[test]
+    fn take_frame_swaps_in_the_spare_buffer() {
+        let device = FakeDevice::new(FRAME_LEN).deliver(filled(1, 100, 0));
+        let log = device.log();
+        let options = CameraOptions { memory: Memory::UserPtr { header_room: 0 }, ..OPTIONS };
+        let mut camera = Camera::with_device("fake", Box::new(device), &options).unwrap();
+        camera.start_streaming().unwrap();
+
+        let mut spare = UserBuffer::new(0, FRAME_LEN as usize);
+        let spare_ptr = spare.image_ptr();
+        let captured_ptr = camera.buffers[1][0].start;
+        assert_eq!(camera.take_frame(&mut spare).unwrap().sequence, 0);
+
+        assert_eq!((spare.image_ptr(), spare.image().len()), (captured_ptr, 100));
+        assert_eq!(camera.buffers[1][0].start, spare_ptr);
+        assert_eq!(log.lock().unwrap().userptrs.last(), Some(&(1, spare_ptr.as_ptr() as usize)));
+    }
+
+    #[test]
+    fn take_frame_keeps_the_buffers_apart_when_the_requeue_fails() {
+        // The four queued by start_streaming succeed, the requeue after the swap fails.
+        let device = FakeDevice::new(FRAME_LEN).deliver(filled(1, 100, 0)).fail("VIDIOC_QBUF", 4, Errno::EIO);
+        let options = CameraOptions { memory: Memory::UserPtr { header_room: 0 }, ..OPTIONS };
+        let mut camera = Camera::with_device("fake", Box::new(device), &options).unwrap();
+        camera.start_streaming().unwrap();
+
+        let mut spare = UserBuffer::new(0, FRAME_LEN as usize);
+        let spare_ptr = spare.image_ptr();
+        let captured_ptr = camera.buffers[1][0].start;
+        assert!(camera.take_frame(&mut spare).is_err());
+        assert_eq!(spare.image_ptr(), spare_ptr);
+        assert_eq!(camera.buffers[1][0].start, captured_ptr);
+    }
+
+    #[test]
+    fn sequence_gaps_count_dropped_frames_until_a_restart() {
+        let mut gaps = SequenceGaps::default();
+        let missed: Vec<u32> = [0, 1, 2, 5, 6, 10, 0, 1, 3].into_iter().map(|sequence| gaps.record(sequence)).collect();
+        assert_eq!(missed, [0, 0, 0, 2, 0, 3, 0, 0, 1]);
+    }
+
+    #[test]
+    fn only_monotonic_driver_timestamps_are_kept() {
+        let mut buffer: v4l2_buffer = unsafe { zeroed() };
+        buffer.sequence = 42;
+        buffer.timestamp.tv_sec = 3;
+        buffer.timestamp.tv_usec = 250;
+        buffer.flags = V4L2_BUF_FLAG_TIMESTAMP_MONOTONIC | V4L2_BUF_FLAG_TSTAMP_SRC_SOE;
+        let info = FrameInfo::from_buffer(&buffer);
+        assert_eq!((info.sequence, info.timestamp_us), (42, 3_000_250));
 
-        Ok(frame_data)
+        // A copied timestamp is on whatever clock the producer used.
+        buffer.flags = V4L2_BUF_FLAG_TIMESTAMP_COPY | V4L2_BUF_FLAG_ERROR;
+        let before = monotonic_micros();
+        let info = FrameInfo::from_buffer(&buffer);
+        assert!(info.timestamp_us >= before);
+        assert!(info.is_corrupt());
     }
 }
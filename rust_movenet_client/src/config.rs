@@ -0,0 +1,161 @@
+use crate::format::{Rect, V4L2_PIX_FMT_MJPEG, V4L2_PIX_FMT_NV12, V4L2_PIX_FMT_NV12M, V4L2_PIX_FMT_YUYV};
+use clap::{Parser, ValueEnum};
+use std::path::PathBuf;
+use rust_movenet_protocol::{PixelFormat, ResponseMode};
+
+/// Pixel formats the client can capture and send.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum CaptureFormat {
+    Yuyv,
+    /// NV12 in a single buffer.
+    Nv12,
+    /// NV12 with separate Y and UV buffers, for multi-planar devices that only offer that.
+    Nv12m,
+    /// Compressed frames, passed to the server as the camera produced them.
+    Mjpeg,
+}
+
+impl CaptureFormat {
+    pub fn pixel_format(self) -> PixelFormat {
+        match self {
+            CaptureFormat::Yuyv => PixelFormat::Yuyv,
+            // Both layouts go over the wire as one contiguous NV12 image.
+            CaptureFormat::Nv12 | CaptureFormat::Nv12m => PixelFormat::Nv12,
+            CaptureFormat::Mjpeg => PixelFormat::Mjpeg,
+        }
+    }
+
+    /// The V4L2 four-character code to request from the driver.
+    pub fn fourcc(self) -> u32 {
+        match self {
+            CaptureFormat::Yuyv => V4L2_PIX_FMT_YUYV,
+            CaptureFormat::Nv12 => V4L2_PIX_FMT_NV12,
+            CaptureFormat::Nv12m => V4L2_PIX_FMT_NV12M,
+            CaptureFormat::Mjpeg => V4L2_PIX_FMT_MJPEG,
+        }
+    }
+}
+
+/// Where frames come from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SourceKind {
+    /// A V4L2 camera.
+    Camera,
+    /// Raw YUYV frames or a Y4M file at `--source-path`.
+    File,
+    /// The images in the `--source-path` directory, in name order.
+    Images,
+    /// Generated color bars.
+    Pattern,
+}
+
+/// How capture buffers are allocated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum MemoryArg {
+    /// Driver-allocated buffers mapped into the client.
+    Mmap,
+    /// Client-allocated buffers that full frames are sent from without a copy.
+    Userptr,
+}
+
+/// What to ask the server to send back for every frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ResponseModeArg {
+    KeypointsOnly,
+    AnnotatedJpeg,
+    Both,
+}
+
+impl From<ResponseModeArg> for ResponseMode {
+    fn from(mode: ResponseModeArg) -> Self {
+        match mode {
+            ResponseModeArg::KeypointsOnly => ResponseMode::KeypointsOnly,
+            ResponseModeArg::AnnotatedJpeg => ResponseMode::AnnotatedJpeg,
+            ResponseModeArg::Both => ResponseMode::Both,
+        }
+    }
+}
+
+#[derive(Parser, Debug, Clone)]
+#[command(about = "Captures frames from a V4L2 camera and offloads MoveNet inference to rust_movenet_server")]
+pub struct Config {
+    /// V4L2 capture device. Picked from `/dev/video*` when not given.
+    #[arg(short, long)]
+    pub device: Option<String>,
+    /// Where frames come from. Everything but `camera` runs without a capture device and
+    /// delivers YUYV.
+    #[arg(long, value_enum, default_value_t = SourceKind::Camera)]
+    pub source: SourceKind,
+    /// File for `--source file`, or directory for `--source images`.
+    #[arg(long)]
+    pub source_path: Option<PathBuf>,
+    /// Start `--source file` or `--source images` over at the end instead of stopping.
+    #[arg(long)]
+    pub source_loop: bool,
+    /// Address of rust_movenet_server.
+    #[arg(short, long, default_value = "10.66.83.44:7878")]
+    pub server: String,
+    /// Number of capture buffers to request from the driver.
+    #[arg(long, default_value_t = 20)]
+    pub buffers: u32,
+    /// Print every V4L2 device with its capabilities and formats, then exit.
+    #[arg(long)]
+    pub list_devices: bool,
+    /// Print the formats and frame sizes the device supports, then exit.
+    #[arg(long)]
+    pub list_formats: bool,
+    /// Print the device's controls with their ranges and current values, then exit.
+    #[arg(long)]
+    pub list_controls: bool,
+    /// TOML file of control values to apply at startup, keyed like `--list-controls` prints them.
+    #[arg(long)]
+    pub controls: Option<PathBuf>,
+    /// Requested capture width; the driver may pick the closest size it supports.
+    #[arg(long, default_value_t = 1920)]
+    pub width: u32,
+    /// Requested capture height; the driver may pick the closest size it supports.
+    #[arg(long, default_value_t = 1080)]
+    pub height: u32,
+    /// Requested capture pixel format.
+    #[arg(long, value_enum, default_value_t = CaptureFormat::Yuyv)]
+    pub format: CaptureFormat,
+    /// Requested capture rate in frames per second; the driver picks the closest it supports.
+    /// Other sources deliver frames at this rate, by default the file's own or 30.
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub fps: Option<u32>,
+    /// Capture only this part of the sensor, as WIDTHxHEIGHT+LEFT+TOP, if the camera can crop.
+    /// Without a scaler the frames shrink to the crop.
+    #[arg(long)]
+    pub crop: Option<Rect>,
+    /// Zoom in on the subject: send only the part of each frame around the keypoints of the
+    /// latest result.
+    #[arg(long)]
+    pub follow: bool,
+    /// Fail if the camera delivers no frame for this many milliseconds, instead of waiting forever.
+    #[arg(long)]
+    pub frame_timeout_ms: Option<u64>,
+    /// How capture buffers are allocated.
+    #[arg(long, value_enum, default_value_t = MemoryArg::Mmap)]
+    pub memory: MemoryArg,
+    /// Export the capture buffers as DMABUF fds for zero-copy consumers.
+    #[arg(long)]
+    pub export_dmabuf: bool,
+    /// Title of the preview window.
+    #[arg(long, default_value = "MoveNet (CPSC 429)")]
+    pub window_title: String,
+    /// Run without a preview window.
+    #[arg(long)]
+    pub headless: bool,
+    /// Letterbox frames on the client and send only the model input tensor.
+    #[arg(long)]
+    pub preprocess: bool,
+    /// Maximum number of frames awaiting a result from the server.
+    #[arg(long, default_value_t = 3)]
+    pub max_in_flight: usize,
+    /// What the server sends back for every frame.
+    #[arg(long, value_enum, default_value_t = ResponseModeArg::KeypointsOnly)]
+    pub response_mode: ResponseModeArg,
+    /// Name this client reports to the server.
+    #[arg(long, default_value = "rust_movenet_client")]
+    pub client_name: String,
+}
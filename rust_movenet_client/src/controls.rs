@@ -0,0 +1,469 @@
+use crate::device::VideoDevice;
+use nix::errno::Errno;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::Error;
+use std::mem::zeroed;
+use std::path::Path;
+use v4l2_sys_mit::*;
+
+/// The kinds of control the client knows how to read and write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlKind {
+    Integer,
+    Integer64,
+    Boolean,
+    Menu,
+    IntegerMenu,
+    Button,
+    /// Strings, bitmasks and compound controls; listed but never read or written.
+    Unsupported,
+}
+
+impl ControlKind {
+    fn from_raw(type_: u32) -> Self {
+        const KINDS: [(u32, ControlKind); 6] = [
+            (v4l2_ctrl_type_V4L2_CTRL_TYPE_INTEGER, ControlKind::Integer),
+            (v4l2_ctrl_type_V4L2_CTRL_TYPE_INTEGER64, ControlKind::Integer64),
+            (v4l2_ctrl_type_V4L2_CTRL_TYPE_BOOLEAN, ControlKind::Boolean),
+            (v4l2_ctrl_type_V4L2_CTRL_TYPE_MENU, ControlKind::Menu),
+            (v4l2_ctrl_type_V4L2_CTRL_TYPE_INTEGER_MENU, ControlKind::IntegerMenu),
+            (v4l2_ctrl_type_V4L2_CTRL_TYPE_BUTTON, ControlKind::Button),
+        ];
+        KINDS
+            .iter()
+            .find(|(raw, _)| *raw == type_)
+            .map_or(ControlKind::Unsupported, |(_, kind)| *kind)
+    }
+}
+
+/// One entry of a menu control. Integer menus carry a number instead of a label.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MenuItem {
+    pub index: u32,
+    pub label: String,
+}
+
+/// A control as reported by `VIDIOC_QUERY_EXT_CTRL`, with its menu entries if it has any.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControlInfo {
+    pub id: u32,
+    /// The driver's name for the control, e.g. `Exposure Time, Absolute`.
+    pub name: String,
+    pub kind: ControlKind,
+    pub minimum: i64,
+    pub maximum: i64,
+    pub step: i64,
+    pub default: i64,
+    pub flags: u32,
+    pub menu: Vec<MenuItem>,
+}
+
+impl ControlInfo {
+    /// The name `v4l2-ctl` uses for the control, e.g. `exposure_time_absolute`. Profiles use
+    /// these so they can be copied straight from `v4l2-ctl --list-ctrls`.
+    pub fn key(&self) -> String {
+        control_key(&self.name)
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.flags & (V4L2_CTRL_FLAG_READ_ONLY | V4L2_CTRL_FLAG_DISABLED) == 0
+            && !matches!(self.kind, ControlKind::Unsupported)
+    }
+
+    pub fn is_readable(&self) -> bool {
+        self.flags & (V4L2_CTRL_FLAG_WRITE_ONLY | V4L2_CTRL_FLAG_DISABLED) == 0
+            && !matches!(self.kind, ControlKind::Button | ControlKind::Unsupported)
+    }
+
+    /// Checks `value` against the control's range, step and menu.
+    pub fn validate(&self, value: i64) -> Result<(), String> {
+        if value < self.minimum || value > self.maximum {
+            return Err(format!("{} must be between {} and {}, not {}", self.key(), self.minimum, self.maximum, value));
+        }
+        if self.kind == ControlKind::Integer && self.step > 1 && (value - self.minimum) % self.step != 0 {
+            return Err(format!("{} must be {} plus a multiple of {}, not {}", self.key(), self.minimum, self.step, value));
+        }
+        if matches!(self.kind, ControlKind::Menu | ControlKind::IntegerMenu)
+            && !self.menu.iter().any(|item| item.index as i64 == value)
+        {
+            return Err(format!("{} has no menu entry {}", self.key(), value));
+        }
+        Ok(())
+    }
+
+    /// Finds the menu entry labelled `label`, ignoring case.
+    pub fn menu_index(&self, label: &str) -> Option<i64> {
+        self.menu
+            .iter()
+            .find(|item| item.label.eq_ignore_ascii_case(label))
+            .map(|item| item.index as i64)
+    }
+}
+
+impl fmt::Display for ControlInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({:?}): min={} max={} step={} default={}", self.key(), self.kind, self.minimum, self.maximum, self.step, self.default)?;
+        if self.flags & V4L2_CTRL_FLAG_INACTIVE != 0 {
+            write!(f, " inactive")?;
+        }
+        if self.flags & V4L2_CTRL_FLAG_READ_ONLY != 0 {
+            write!(f, " read-only")?;
+        }
+        for item in &self.menu {
+            write!(f, "\n        {}: {}", item.index, item.label)?;
+        }
+        Ok(())
+    }
+}
+
+/// Lower-cases `name` and joins its words with underscores, the way `v4l2-ctl` does.
+pub fn control_key(name: &str) -> String {
+    let mut key = String::with_capacity(name.len());
+    for c in name.chars() {
+        if c.is_ascii_alphanumeric() {
+            key.push(c.to_ascii_lowercase());
+        } else if !key.is_empty() && !key.ends_with('_') {
+            key.push('_');
+        }
+    }
+    key.trim_end_matches('_').to_string()
+}
+
+fn c_string(bytes: &[u8]) -> String {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..len]).into_owned()
+}
+
+/// Lists every control the device has, skipping control class headings and disabled controls.
+pub fn enumerate_controls(device: &dyn VideoDevice) -> Result<Vec<ControlInfo>, Box<dyn std::error::Error>> {
+    let mut controls = Vec::new();
+    let mut id = 0;
+    let mut extended = true;
+    loop {
+        let query = match query_next_control(device, id, &mut extended) {
+            Ok(query) => query,
+            // EINVAL marks the end of the list.
+            Err(Errno::EINVAL) => break,
+            Err(e) => return Err(Box::new(Error::from(e))),
+        };
+        id = query.id;
+
+        let kind = ControlKind::from_raw(query.type_);
+        if query.type_ == v4l2_ctrl_type_V4L2_CTRL_TYPE_CTRL_CLASS || query.flags & V4L2_CTRL_FLAG_DISABLED != 0 {
+            continue;
+        }
+
+        let menu = match kind {
+            ControlKind::Menu | ControlKind::IntegerMenu => enumerate_menu(device, &query, kind)?,
+            _ => Vec::new(),
+        };
+        controls.push(ControlInfo {
+            id: query.id,
+            name: c_string(&query.name.map(|c| c as u8)),
+            kind,
+            minimum: query.minimum,
+            maximum: query.maximum,
+            step: query.step as i64,
+            default: query.default_value,
+            flags: query.flags,
+            menu,
+        });
+    }
+    Ok(controls)
+}
+
+/// Queries the first control after `id`. `VIDIOC_QUERY_EXT_CTRL` is used while `extended` is
+/// set, since only it reports the full range of 64-bit controls; drivers without it answer
+/// ENOTTY, which clears `extended` and falls back to `VIDIOC_QUERYCTRL` from then on.
+fn query_next_control(device: &dyn VideoDevice, id: u32, extended: &mut bool) -> nix::Result<v4l2_query_ext_ctrl> {
+    if *extended {
+        let mut query = v4l2_query_ext_ctrl { id: id | V4L2_CTRL_FLAG_NEXT_CTRL, ..unsafe { zeroed() } };
+        match device.query_ext_control(&mut query) {
+            Err(Errno::ENOTTY) => *extended = false,
+            result => return result.map(|()| query),
+        }
+    }
+
+    let mut query = v4l2_queryctrl { id: id | V4L2_CTRL_FLAG_NEXT_CTRL, ..unsafe { zeroed() } };
+    device.query_control(&mut query)?;
+    Ok(v4l2_query_ext_ctrl {
+        id: query.id,
+        type_: query.type_,
+        name: query.name.map(|c| c as std::os::raw::c_char),
+        minimum: query.minimum.into(),
+        maximum: query.maximum.into(),
+        step: query.step as u64,
+        default_value: query.default_value.into(),
+        flags: query.flags,
+        ..unsafe { zeroed() }
+    })
+}
+
+/// Menu indices looked up per control. Real menus have a handful of entries; a range much
+/// wider than this is a sparse integer menu or a buggy driver, and every index costs an ioctl.
+const MAX_MENU_INDICES: u32 = 256;
+
+fn enumerate_menu(device: &dyn VideoDevice, query: &v4l2_query_ext_ctrl, kind: ControlKind) -> Result<Vec<MenuItem>, Box<dyn std::error::Error>> {
+    let first = query.minimum.clamp(0, u32::MAX.into()) as u32;
+    let last = query.maximum.clamp(0, first.saturating_add(MAX_MENU_INDICES - 1).into()) as u32;
+    if query.maximum > last.into() {
+        eprintln!("Menu of control {:#x} goes up to {}; only reading entries {} to {}", query.id, query.maximum, first, last);
+    }
+
+    let mut items = Vec::new();
+    for index in first..=last {
+        let mut menu = v4l2_querymenu { id: query.id, index, ..unsafe { zeroed() } };
+        // Menus may have holes; the driver rejects the missing indices with EINVAL.
+        match device.query_menu(&mut menu) {
+            Ok(_) => {}
+            Err(Errno::EINVAL) => continue,
+            Err(e) => return Err(Box::new(Error::from(e))),
+        }
+        let label = if kind == ControlKind::IntegerMenu {
+            unsafe { menu.__bindgen_anon_1.value }.to_string()
+        } else {
+            c_string(&unsafe { menu.__bindgen_anon_1.name })
+        };
+        items.push(MenuItem { index, label });
+    }
+    Ok(items)
+}
+
+/// Reads the current value of `control`.
+pub fn get_control(device: &dyn VideoDevice, control: &ControlInfo) -> Result<i64, Box<dyn std::error::Error>> {
+    if control.kind == ControlKind::Integer64 {
+        // 64-bit controls are only reachable through the extended control ioctls.
+        let mut ext = v4l2_ext_control { id: control.id, ..unsafe { zeroed() } };
+        let mut request = ext_controls(std::slice::from_mut(&mut ext));
+        device.get_ext_controls(&mut request).map_err(Error::from)?;
+        return Ok(unsafe { ext.__bindgen_anon_1.value64 });
+    }
+
+    let mut value = v4l2_control { id: control.id, value: 0 };
+    device.get_ctrl(&mut value).map_err(Error::from)?;
+    Ok(value.value as i64)
+}
+
+/// Writes `value` to `control` after checking it against the control's range.
+pub fn set_control(device: &dyn VideoDevice, control: &ControlInfo, value: i64) -> Result<(), Box<dyn std::error::Error>> {
+    control.validate(value)?;
+    if control.kind == ControlKind::Integer64 {
+        return set_controls(device, &[(control, value)]);
+    }
+
+    let mut request = v4l2_control { id: control.id, value: value as i32 };
+    device.set_ctrl(&mut request).map_err(Error::from)?;
+    Ok(())
+}
+
+/// Writes several controls in one `VIDIOC_S_EXT_CTRLS` call, in order. Drivers without
+/// extended control support get one `VIDIOC_S_CTRL` per control instead.
+pub fn set_controls(device: &dyn VideoDevice, values: &[(&ControlInfo, i64)]) -> Result<(), Box<dyn std::error::Error>> {
+    for (control, value) in values {
+        control.validate(*value)?;
+    }
+
+    let mut ext: Vec<v4l2_ext_control> = values
+        .iter()
+        .map(|(control, value)| {
+            let mut ext = v4l2_ext_control { id: control.id, ..unsafe { zeroed() } };
+            if control.kind == ControlKind::Integer64 {
+                ext.__bindgen_anon_1.value64 = *value;
+            } else {
+                ext.__bindgen_anon_1.value = *value as i32;
+            }
+            ext
+        })
+        .collect();
+    let mut request = ext_controls(&mut ext);
+    match device.set_ext_controls(&mut request) {
+        Ok(_) => Ok(()),
+        Err(Errno::ENOTTY) => {
+            for (control, value) in values {
+                set_control(device, control, *value)?;
+            }
+            Ok(())
+        }
+        Err(e) => {
+            // error_idx equal to count means the driver could not pin the failure on one control.
+            let failed = values
+                .get(request.error_idx as usize)
+                .map(|(control, value)| format!(" (setting {} to {})", control.key(), value))
+                .unwrap_or_default();
+            Err(Box::new(Error::other(format!("{}{}", Error::from(e), failed))))
+        }
+    }
+}
+
+fn ext_controls(controls: &mut [v4l2_ext_control]) -> v4l2_ext_controls {
+    let mut request = v4l2_ext_controls {
+        count: controls.len() as u32,
+        controls: controls.as_mut_ptr(),
+        ..unsafe { zeroed() }
+    };
+    request.__bindgen_anon_1.which = V4L2_CTRL_WHICH_CUR_VAL;
+    request
+}
+
+/// Control values saved in a TOML file, keyed by [`ControlInfo::key`] and applied in file
+/// order, so auto modes can be switched off before the manual values they gate are written:
+///
+/// ```toml
+/// auto_exposure = "Manual Mode"
+/// exposure_time_absolute = 250
+/// focus_automatic_continuous = false
+/// focus_absolute = 0
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct ControlProfile {
+    entries: toml::Table,
+}
+
+impl ControlProfile {
+    pub fn load(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(Self::from_toml(&std::fs::read_to_string(path)?)?)
+    }
+
+    pub fn from_toml(text: &str) -> Result<Self, toml::de::Error> {
+        Ok(ControlProfile { entries: text.parse()? })
+    }
+
+    /// Matches every entry to one of `controls` and turns it into a raw value. Menu entries
+    /// may be given by label or by index; booleans are written as 0 or 1.
+    pub fn resolve<'a>(&self, controls: &'a [ControlInfo]) -> Result<Vec<(&'a ControlInfo, i64)>, String> {
+        self.entries
+            .iter()
+            .map(|(key, value)| {
+                let control = controls
+                    .iter()
+                    .find(|control| control.key() == *key)
+                    .ok_or_else(|| format!("the device has no control named {}", key))?;
+                if !control.is_writable() {
+                    return Err(format!("{} cannot be written", key));
+                }
+                let value = match value {
+                    toml::Value::Integer(value) => *value,
+                    toml::Value::Boolean(value) => *value as i64,
+                    toml::Value::String(label) => control
+                        .menu_index(label)
+                        .ok_or_else(|| format!("{} has no menu entry {:?}", key, label))?,
+                    other => return Err(format!("{} must be an integer, boolean or menu label, not {}", key, other.type_str())),
+                };
+                control.validate(value)?;
+                Ok((control, value))
+            })
+            .collect()
+    }
+}
+
+/// Prints every control `device_path` exposes along with its current value.
+pub fn print_controls(device_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let fd = OpenOptions::new().read(true).write(true).open(device_path)?;
+    for control in enumerate_controls(&fd)? {
+        if control.is_readable() {
+            println!("{} value={}", control, get_control(&fd, &control)?);
+        } else {
+            println!("{}", control);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::FakeDevice;
+
+    fn exposure_auto() -> ControlInfo {
+        ControlInfo {
+            id: V4L2_CID_EXPOSURE_AUTO,
+            name: "Auto Exposure".to_string(),
+            kind: ControlKind::Menu,
+            minimum: 0,
+            maximum: 3,
+            step: 1,
+            default: 3,
+            flags: 0,
+            menu: vec![
+                MenuItem { index: 1, label: "Manual Mode".to_string() },
+                MenuItem { index: 3, label: "Aperture Priority Mode".to_string() },
+            ],
+        }
+    }
+
+    #[test]
+    fn keys_match_v4l2_ctl() {
+        assert_eq!(control_key("Exposure Time, Absolute"), "exposure_time_absolute");
+        assert_eq!(control_key("White Balance, Automatic"), "white_balance_automatic");
+        assert_eq!(control_key("Focus (absolute)"), "focus_absolute");
+    }
+
+    #[test]
+    fn values_are_checked_against_the_menu() {
+        let control = exposure_auto();
+        assert!(control.validate(1).is_ok());
+        assert!(control.validate(2).unwrap_err().contains("no menu entry"));
+        assert!(control.validate(4).unwrap_err().contains("between 0 and 3"));
+        assert_eq!(control.menu_index("manual mode"), Some(1));
+    }
+
+    #[test]
+    fn profiles_resolve_labels_and_keep_file_order() {
+        let exposure = ControlInfo {
+            id: V4L2_CID_EXPOSURE_ABSOLUTE,
+            name: "Exposure Time, Absolute".to_string(),
+            kind: ControlKind::Integer,
+            minimum: 3,
+            maximum: 2047,
+            step: 1,
+            default: 250,
+            flags: V4L2_CTRL_FLAG_INACTIVE,
+            menu: Vec::new(),
+        };
+        let controls = [exposure, exposure_auto()];
+
+        let profile = ControlProfile::from_toml("auto_exposure = \"manual mode\"\nexposure_time_absolute = 100\n").unwrap();
+        let values: Vec<(u32, i64)> = profile.resolve(&controls).unwrap().iter().map(|(c, v)| (c.id, *v)).collect();
+        assert_eq!(values, vec![(V4L2_CID_EXPOSURE_AUTO, 1), (V4L2_CID_EXPOSURE_ABSOLUTE, 100)]);
+
+        let unknown = ControlProfile::from_toml("exposure = 100").unwrap();
+        assert!(unknown.resolve(&controls).unwrap_err().contains("no control named exposure"));
+        let out_of_range = ControlProfile::from_toml("exposure_time_absolute = 1").unwrap();
+        assert!(out_of_range.resolve(&controls).is_err());
+    }
+
+    #[test]
+    fn integer_values_must_land_on_a_step() {
+        let control = ControlInfo { kind: ControlKind::Integer, minimum: 3, maximum: 2047, step: 4, menu: Vec::new(), ..exposure_auto() };
+        assert!(control.validate(7).is_ok());
+        assert!(control.validate(8).is_err());
+    }
+
+    #[test]
+    fn sparse_menus_are_only_read_up_to_a_limit() {
+        let item = |index, value| {
+            let mut menu = v4l2_querymenu { id: V4L2_CID_LINK_FREQ, index, ..unsafe { zeroed() } };
+            menu.__bindgen_anon_1.value = value;
+            menu
+        };
+        let device = FakeDevice::new(0).with_menu_item(item(0, 300_000_000)).with_menu_item(item(9, 600_000_000)).with_menu_item(item(1000, 1));
+        let query = v4l2_query_ext_ctrl { id: V4L2_CID_LINK_FREQ, minimum: 0, maximum: i64::MAX, ..unsafe { zeroed() } };
+
+        let items = enumerate_menu(&device, &query, ControlKind::IntegerMenu).unwrap();
+        let expected = [MenuItem { index: 0, label: "300000000".to_string() }, MenuItem { index: 9, label: "600000000".to_string() }];
+        assert_eq!(items, expected);
+        assert_eq!(device.log().lock().unwrap().calls.len(), MAX_MENU_INDICES as usize);
+    }
+
+    #[test]
+    fn drivers_without_query_ext_ctrl_fall_back_to_queryctrl() {
+        let device = FakeDevice::new(0);
+        assert!(enumerate_controls(&device).unwrap().is_empty());
+        assert_eq!(device.log().lock().unwrap().calls, ["VIDIOC_QUERY_EXT_CTRL"]);
+
+        let device = FakeDevice::new(0).fail("VIDIOC_QUERY_EXT_CTRL", 0, Errno::ENOTTY);
+        assert!(enumerate_controls(&device).unwrap().is_empty());
+        assert_eq!(device.log().lock().unwrap().calls, ["VIDIOC_QUERY_EXT_CTRL", "VIDIOC_QUERYCTRL"]);
+    }
+}
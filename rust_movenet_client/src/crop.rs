@@ -0,0 +1,244 @@
+use crate::format::Rect;
+use rust_movenet_protocol::{FrameHeader, PixelFormat};
+
+/// With fewer confident keypoints than this the subject counts as lost.
+const MIN_KEYPOINTS: usize = 4;
+/// Room left around the keypoints on every side, as a fraction of the longer side of their
+/// bounding box, so hands and feet the model missed stay in view.
+const MARGIN: f32 = 0.25;
+/// How far the crop moves toward the subject with each result. Lower is steadier but lags more.
+const SMOOTHING: f32 = 0.3;
+
+/// A digital zoom that follows the subject. Each result moves the crop a step toward the part
+/// of the frame its keypoints are in, so the next frames are cut down to that before they are
+/// sent and the subject fills more of the model input. With nobody in view it zooms back out
+/// to the whole frame.
+pub struct CropTracker {
+    width: f32,
+    height: f32,
+    /// Smallest crop side, so a far-away subject is not blown up past the model input size.
+    min_size: f32,
+    /// Left, top, right and bottom edge of the crop in frame pixels. Kept fractional so small
+    /// steps add up instead of rounding away.
+    edges: [f32; 4],
+}
+
+impl CropTracker {
+    /// Starts out showing the whole `width`x`height` frame.
+    pub fn new(width: u32, height: u32, min_size: u32) -> Self {
+        CropTracker {
+            width: width as f32,
+            height: height as f32,
+            min_size: min_size as f32,
+            edges: [0.0, 0.0, width as f32, height as f32],
+        }
+    }
+
+    /// The part of the frame to send next. Its corners are on even pixels so the YUYV pixel
+    /// pairs and NV12 chroma samples it cuts through stay whole.
+    pub fn crop(&self) -> Rect {
+        let [left, top, right, bottom] = self.edges;
+        let even = |value: f32| value.round() as u32 & !1;
+        let (frame_width, frame_height) = (self.width as u32, self.height as u32);
+        let width = even(right - left).clamp(2, frame_width & !1);
+        let height = even(bottom - top).clamp(2, frame_height & !1);
+        Rect {
+            left: even(left).min((frame_width - width) & !1),
+            top: even(top).min((frame_height - height) & !1),
+            width,
+            height,
+        }
+    }
+
+    /// Moves the crop toward the subject. `keypoints` are the `[y, x, confidence]` triples of
+    /// a frame cut down to `crop`, normalized to it; those below `threshold` are ignored.
+    pub fn update(&mut self, keypoints: &[f32], crop: Rect, threshold: f32) {
+        let target = self.target(keypoints, crop, threshold);
+        for (edge, target) in self.edges.iter_mut().zip(target) {
+            *edge += (target - *edge) * SMOOTHING;
+        }
+    }
+
+    /// Where the crop should end up: a square around the confident keypoints, which the model's
+    /// square input takes without padding, or the whole frame if there are too few of them.
+    fn target(&self, keypoints: &[f32], crop: Rect, threshold: f32) -> [f32; 4] {
+        let points: Vec<(f32, f32)> = keypoints
+            .chunks_exact(3)
+            .filter(|keypoint| keypoint[2] >= threshold)
+            .map(|keypoint| (crop.left as f32 + keypoint[1] * crop.width as f32, crop.top as f32 + keypoint[0] * crop.height as f32))
+            .collect();
+        if points.len() < MIN_KEYPOINTS {
+            return [0.0, 0.0, self.width, self.height];
+        }
+
+        let (mut left, mut top, mut right, mut bottom) = (f32::MAX, f32::MAX, f32::MIN, f32::MIN);
+        for (x, y) in points {
+            left = left.min(x);
+            top = top.min(y);
+            right = right.max(x);
+            bottom = bottom.max(y);
+        }
+        let side = (right - left).max(bottom - top) * (1.0 + 2.0 * MARGIN);
+        let width = side.max(self.min_size).min(self.width);
+        let height = side.max(self.min_size).min(self.height);
+        let center_x = ((left + right) / 2.0).clamp(width / 2.0, self.width - width / 2.0);
+        let center_y = ((top + bottom) / 2.0).clamp(height / 2.0, self.height - height / 2.0);
+        [center_x - width / 2.0, center_y - height / 2.0, center_x + width / 2.0, center_y + height / 2.0]
+    }
+}
+
+/// Cuts `rect` out of a frame given as its memory planes, and returns it as one contiguous
+/// image along with a header describing it. `rect` has to have its corners on even pixels, as
+/// [`CropTracker::crop`] makes them. A frame split into a Y and a UV plane has rows of
+/// `header.stride` bytes in both, as the camera only captures formats where they match (see
+/// [`PixFormat::uniform_stride`](crate::format::PixFormat::uniform_stride)).
+///
+/// Fails on compressed frames, which have to be decoded before they can be cropped, and on
+/// frames too short for their header or too small for `rect`.
+pub fn crop_frame(planes: &[&[u8]], header: &FrameHeader, rect: Rect) -> Result<(FrameHeader, Vec<u8>), String> {
+    let bytes_per_pixel = match header.pixel_format {
+        PixelFormat::Yuyv => 2,
+        PixelFormat::Bgr24 => 3,
+        PixelFormat::Nv12 => 1,
+        PixelFormat::Mjpeg => return Err("MJPEG frames cannot be cropped".to_string()),
+    };
+    if rect.left + rect.width > header.width || rect.top + rect.height > header.height {
+        return Err(format!("crop {} does not fit in a {}x{} frame", rect, header.width, header.height));
+    }
+    let stride = header.stride as usize;
+    if stride < header.width as usize * bytes_per_pixel {
+        return Err(format!("stride {} is too small for a width of {}", stride, header.width));
+    }
+
+    // Every UV row covers two Y rows, and its interleaved U and V bytes two Y columns. Either
+    // way the UV rows are `stride` apart, like the Y rows.
+    let luma_len = stride * header.height as usize;
+    let (luma, chroma) = match planes {
+        [single] if single.len() >= header.min_payload_len() => {
+            let (luma, chroma) = single.split_at(luma_len);
+            (luma, chroma)
+        }
+        [luma, chroma] if luma.len() >= luma_len && chroma.len() >= header.min_payload_len() - luma_len => (*luma, *chroma),
+        _ => {
+            let len: usize = planes.iter().map(|plane| plane.len()).sum();
+            return Err(format!("frame of {} bytes is too short for {}x{}", len, header.width, header.height));
+        }
+    };
+
+    let row_len = rect.width as usize * bytes_per_pixel;
+    let cropped_header = FrameHeader {
+        width: rect.width,
+        height: rect.height,
+        stride: row_len as u32,
+        ..*header
+    };
+    let mut cropped = Vec::with_capacity(cropped_header.min_payload_len());
+    let mut copy_rows = |plane: &[u8], rows: std::ops::Range<u32>| {
+        for row in rows {
+            let start = row as usize * stride + rect.left as usize * bytes_per_pixel;
+            cropped.extend_from_slice(&plane[start..start + row_len]);
+        }
+    };
+
+    copy_rows(luma, rect.top..rect.top + rect.height);
+    if header.pixel_format == PixelFormat::Nv12 {
+        copy_rows(chroma, rect.top / 2..(rect.top + rect.height) / 2);
+    }
+    Ok((cropped_header, cropped))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(width: u32, height: u32, stride: u32, pixel_format: PixelFormat) -> FrameHeader {
+        FrameHeader { sequence: 7, capture_timestamp_us: 1, width, height, stride, pixel_format, letterbox: None }
+    }
+
+    /// Keypoints at the given `(x, y)` frame pixels of a 640x480 frame, fully confident.
+    fn keypoints(points: &[(f32, f32)]) -> Vec<f32> {
+        points.iter().flat_map(|&(x, y)| [y / 480.0, x / 640.0, 1.0]).collect()
+    }
+
+    #[test]
+    fn crop_closes_in_on_the_subject() {
+        let mut tracker = CropTracker::new(640, 480, 192);
+        assert_eq!(tracker.crop(), Rect::full(640, 480));
+
+        // A 200 pixel tall subject on the right, with one keypoint too unsure to count.
+        let mut subject = keypoints(&[(500.0, 200.0), (520.0, 300.0), (480.0, 400.0), (510.0, 380.0), (0.0, 0.0)]);
+        subject[14] = 0.1;
+        for _ in 0..50 {
+            let crop = tracker.crop();
+            tracker.update(&subject, Rect::full(640, 480), 0.25);
+            assert!([crop.left, crop.top, crop.width, crop.height].iter().all(|value| value.is_multiple_of(2)));
+        }
+        // 200 pixels plus a quarter on each side, pushed back inside the frame.
+        assert_eq!(tracker.crop(), Rect { left: 340, top: 150, width: 300, height: 300 });
+    }
+
+    #[test]
+    fn crop_keeps_its_minimum_size_and_zooms_out_without_a_subject() {
+        let mut tracker = CropTracker::new(640, 480, 192);
+        let subject = keypoints(&[(300.0, 200.0), (310.0, 210.0), (320.0, 220.0), (330.0, 230.0)]);
+        for _ in 0..50 {
+            tracker.update(&subject, Rect::full(640, 480), 0.25);
+        }
+        assert_eq!(tracker.crop(), Rect { left: 218, top: 118, width: 192, height: 192 });
+
+        // Keypoints normalized to that crop land back in the same place of the frame.
+        let crop = tracker.crop();
+        let in_crop: Vec<f32> = subject
+            .chunks_exact(3)
+            .flat_map(|k| [(k[0] * 480.0 - crop.top as f32) / 192.0, (k[1] * 640.0 - crop.left as f32) / 192.0, 1.0])
+            .collect();
+        tracker.update(&in_crop, crop, 0.25);
+        assert_eq!(tracker.crop(), crop);
+
+        for _ in 0..50 {
+            tracker.update(&[], crop, 0.25);
+        }
+        assert_eq!(tracker.crop(), Rect::full(640, 480));
+    }
+
+    #[test]
+    fn yuyv_frames_are_cropped_row_by_row() {
+        // 4x2 pixels with 4 bytes of row padding; every byte holds its own offset.
+        let frame: Vec<u8> = (0..24).collect();
+        let (cropped_header, cropped) = crop_frame(&[&frame], &header(4, 2, 12, PixelFormat::Yuyv), Rect { left: 2, top: 0, width: 2, height: 2 }).unwrap();
+        assert_eq!(cropped, [4, 5, 6, 7, 16, 17, 18, 19]);
+        assert_eq!(cropped_header, header(2, 2, 4, PixelFormat::Yuyv));
+    }
+
+    #[test]
+    fn nv12_frames_keep_their_chroma_whether_packed_or_planar() {
+        // 4x4 luma followed by 4x2 interleaved chroma.
+        let frame: Vec<u8> = (0..24).collect();
+        let rect = Rect { left: 2, top: 2, width: 2, height: 2 };
+        let expected = [10, 11, 14, 15, 22, 23];
+
+        let (cropped_header, cropped) = crop_frame(&[&frame], &header(4, 4, 4, PixelFormat::Nv12), rect).unwrap();
+        assert_eq!(cropped, expected);
+        assert_eq!(cropped_header.min_payload_len(), cropped.len());
+
+        let (luma, chroma) = frame.split_at(16);
+        let (_, cropped) = crop_frame(&[luma, chroma], &header(4, 4, 4, PixelFormat::Nv12), rect).unwrap();
+        assert_eq!(cropped, expected);
+    }
+
+    #[test]
+    fn frames_that_cannot_be_cropped_are_refused() {
+        let frame: Vec<u8> = (0..24).collect();
+        let rect = Rect { left: 2, top: 2, width: 2, height: 2 };
+        let nv12 = header(4, 4, 4, PixelFormat::Nv12);
+
+        // Chroma missing, whether packed or planar.
+        assert!(crop_frame(&[&frame[..16]], &nv12, rect).is_err());
+        assert!(crop_frame(&[&frame[..16], &frame[16..20]], &nv12, rect).is_err());
+        assert!(crop_frame(&[], &nv12, rect).is_err());
+        // A crop reaching past the frame, a stride shorter than a row, and a compressed frame.
+        assert!(crop_frame(&[&frame], &nv12, Rect { left: 2, top: 0, width: 4, height: 2 }).is_err());
+        assert!(crop_frame(&[&frame], &header(4, 2, 4, PixelFormat::Yuyv), Rect { left: 0, top: 0, width: 2, height: 2 }).is_err());
+        assert!(crop_frame(&[&frame], &header(4, 2, 12, PixelFormat::Mjpeg), rect).is_err());
+    }
+}
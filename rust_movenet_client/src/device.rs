@@ -0,0 +1,449 @@
+use crate::ioctl_macros;
+use nix::sys::mman::{mmap, MapFlags, ProtFlags};
+use std::ffi::c_void;
+use std::fs::File;
+use std::num::NonZeroUsize;
+use std::os::fd::AsFd;
+use std::os::unix::prelude::AsRawFd;
+use std::ptr::NonNull;
+use v4l2_sys_mit::*;
+
+/// The operations the client performs on a V4L2 device node: one method per ioctl, named
+/// after it, and mapping buffer memory. A [`File`] opened on the node sends them to the
+/// kernel; tests substitute a scripted fake. Errors are the errno the driver returned.
+pub trait VideoDevice: AsFd + Send {
+    fn query_capabilities(&self, capabilities: &mut v4l2_capability) -> nix::Result<()>;
+    fn enum_formats(&self, desc: &mut v4l2_fmtdesc) -> nix::Result<()>;
+    fn get_format(&self, format: &mut v4l2_format) -> nix::Result<()>;
+    fn set_format(&self, format: &mut v4l2_format) -> nix::Result<()>;
+    fn request_buffers(&self, reqbufs: &mut v4l2_requestbuffers) -> nix::Result<()>;
+    fn query_buffer(&self, buffer: &mut v4l2_buffer) -> nix::Result<()>;
+    fn queue_buffer(&self, buffer: &mut v4l2_buffer) -> nix::Result<()>;
+    fn export_buffer(&self, export: &mut v4l2_exportbuffer) -> nix::Result<()>;
+    fn dequeue_buffer(&self, buffer: &mut v4l2_buffer) -> nix::Result<()>;
+    fn stream_on(&self, buf_type: v4l2_buf_type) -> nix::Result<()>;
+    fn stream_off(&self, buf_type: v4l2_buf_type) -> nix::Result<()>;
+    fn get_stream_params(&self, params: &mut v4l2_streamparm) -> nix::Result<()>;
+    fn set_stream_params(&self, params: &mut v4l2_streamparm) -> nix::Result<()>;
+    fn get_ctrl(&self, control: &mut v4l2_control) -> nix::Result<()>;
+    fn set_ctrl(&self, control: &mut v4l2_control) -> nix::Result<()>;
+    fn query_control(&self, query: &mut v4l2_queryctrl) -> nix::Result<()>;
+    fn query_ext_control(&self, query: &mut v4l2_query_ext_ctrl) -> nix::Result<()>;
+    fn query_menu(&self, menu: &mut v4l2_querymenu) -> nix::Result<()>;
+    fn get_ext_controls(&self, controls: &mut v4l2_ext_controls) -> nix::Result<()>;
+    fn set_ext_controls(&self, controls: &mut v4l2_ext_controls) -> nix::Result<()>;
+    fn enum_frame_sizes(&self, frmsize: &mut v4l2_frmsizeenum) -> nix::Result<()>;
+    fn enum_frame_intervals(&self, frmival: &mut v4l2_frmivalenum) -> nix::Result<()>;
+    fn get_selection(&self, selection: &mut v4l2_selection) -> nix::Result<()>;
+    fn set_selection(&self, selection: &mut v4l2_selection) -> nix::Result<()>;
+
+    /// Maps `length` bytes of a driver buffer at the `offset` `VIDIOC_QUERYBUF` reported for
+    /// it, shared and writable. The mapping is released with `munmap`.
+    fn map(&self, length: NonZeroUsize, offset: u32) -> nix::Result<NonNull<c_void>>;
+}
+
+impl VideoDevice for File {
+    fn query_capabilities(&self, capabilities: &mut v4l2_capability) -> nix::Result<()> {
+        unsafe { ioctl_macros::query_capabilities(self.as_raw_fd(), capabilities) }.map(drop)
+    }
+
+    fn enum_formats(&self, desc: &mut v4l2_fmtdesc) -> nix::Result<()> {
+        unsafe { ioctl_macros::enum_formats(self.as_raw_fd(), desc) }.map(drop)
+    }
+
+    fn get_format(&self, format: &mut v4l2_format) -> nix::Result<()> {
+        unsafe { ioctl_macros::get_format(self.as_raw_fd(), format) }.map(drop)
+    }
+
+    fn set_format(&self, format: &mut v4l2_format) -> nix::Result<()> {
+        unsafe { ioctl_macros::set_format(self.as_raw_fd(), format) }.map(drop)
+    }
+
+    fn request_buffers(&self, reqbufs: &mut v4l2_requestbuffers) -> nix::Result<()> {
+        unsafe { ioctl_macros::request_buffers(self.as_raw_fd(), reqbufs) }.map(drop)
+    }
+
+    fn query_buffer(&self, buffer: &mut v4l2_buffer) -> nix::Result<()> {
+        unsafe { ioctl_macros::query_buffers(self.as_raw_fd(), buffer) }.map(drop)
+    }
+
+    fn queue_buffer(&self, buffer: &mut v4l2_buffer) -> nix::Result<()> {
+        unsafe { ioctl_macros::q_buffer(self.as_raw_fd(), buffer) }.map(drop)
+    }
+
+    fn export_buffer(&self, export: &mut v4l2_exportbuffer) -> nix::Result<()> {
+        unsafe { ioctl_macros::export_buffer(self.as_raw_fd(), export) }.map(drop)
+    }
+
+    fn dequeue_buffer(&self, buffer: &mut v4l2_buffer) -> nix::Result<()> {
+        unsafe { ioctl_macros::dq_buffer(self.as_raw_fd(), buffer) }.map(drop)
+    }
+
+    fn stream_on(&self, buf_type: v4l2_buf_type) -> nix::Result<()> {
+        unsafe { ioctl_macros::vidioc_streamon(self.as_raw_fd(), &buf_type as *const _ as *const i32) }.map(drop)
+    }
+
+    fn stream_off(&self, buf_type: v4l2_buf_type) -> nix::Result<()> {
+        unsafe { ioctl_macros::vidioc_streamoff(self.as_raw_fd(), &buf_type as *const _ as *const i32) }.map(drop)
+    }
+
+    fn get_stream_params(&self, params: &mut v4l2_streamparm) -> nix::Result<()> {
+        unsafe { ioctl_macros::get_stream_params(self.as_raw_fd(), params) }.map(drop)
+    }
+
+    fn set_stream_params(&self, params: &mut v4l2_streamparm) -> nix::Result<()> {
+        unsafe { ioctl_macros::set_stream_params(self.as_raw_fd(), params) }.map(drop)
+    }
+
+    fn get_ctrl(&self, control: &mut v4l2_control) -> nix::Result<()> {
+        unsafe { ioctl_macros::get_ctrl(self.as_raw_fd(), control) }.map(drop)
+    }
+
+    fn set_ctrl(&self, control: &mut v4l2_control) -> nix::Result<()> {
+        unsafe { ioctl_macros::set_ctrl(self.as_raw_fd(), control) }.map(drop)
+    }
+
+    fn query_control(&self, query: &mut v4l2_queryctrl) -> nix::Result<()> {
+        unsafe { ioctl_macros::query_control(self.as_raw_fd(), query) }.map(drop)
+    }
+
+    fn query_ext_control(&self, query: &mut v4l2_query_ext_ctrl) -> nix::Result<()> {
+        unsafe { ioctl_macros::query_ext_control(self.as_raw_fd(), query) }.map(drop)
+    }
+
+    fn query_menu(&self, menu: &mut v4l2_querymenu) -> nix::Result<()> {
+        unsafe { ioctl_macros::query_menu(self.as_raw_fd(), menu) }.map(drop)
+    }
+
+    fn get_ext_controls(&self, controls: &mut v4l2_ext_controls) -> nix::Result<()> {
+        unsafe { ioctl_macros::get_ext_controls(self.as_raw_fd(), controls) }.map(drop)
+    }
+
+    fn set_ext_controls(&self, controls: &mut v4l2_ext_controls) -> nix::Result<()> {
+        unsafe { ioctl_macros::set_ext_controls(self.as_raw_fd(), controls) }.map(drop)
+    }
+
+    fn enum_frame_sizes(&self, frmsize: &mut v4l2_frmsizeenum) -> nix::Result<()> {
+        unsafe { ioctl_macros::enum_frame_sizes(self.as_raw_fd(), frmsize) }.map(drop)
+    }
+
+    fn enum_frame_intervals(&self, frmival: &mut v4l2_frmivalenum) -> nix::Result<()> {
+        unsafe { ioctl_macros::enum_frame_intervals(self.as_raw_fd(), frmival) }.map(drop)
+    }
+
+    fn get_selection(&self, selection: &mut v4l2_selection) -> nix::Result<()> {
+        unsafe { ioctl_macros::get_selection(self.as_raw_fd(), selection) }.map(drop)
+    }
+
+    fn set_selection(&self, selection: &mut v4l2_selection) -> nix::Result<()> {
+        unsafe { ioctl_macros::set_selection(self.as_raw_fd(), selection) }.map(drop)
+    }
+
+    fn map(&self, length: NonZeroUsize, offset: u32) -> nix::Result<NonNull<c_void>> {
+        unsafe { mmap(None, length, ProtFlags::PROT_READ | ProtFlags::PROT_WRITE, MapFlags::MAP_SHARED, self, offset.into()) }
+    }
+}
+
+#[cfg(test)]
+pub use fake::{FakeDevice, FakeLog};
+
+#[cfg(test)]
+mod fake {
+    use super::*;
+    use nix::errno::Errno;
+    use nix::sys::mman::mmap_anonymous;
+    use std::collections::VecDeque;
+    use std::mem::zeroed;
+    use std::os::fd::BorrowedFd;
+    use std::sync::{Arc, Mutex};
+
+    /// What a [`FakeDevice`] was asked to do, kept after the device itself is dropped.
+    #[derive(Debug, Default)]
+    pub struct FakeLog {
+        /// Every call in order, as the ioctl name followed by the fields that matter.
+        pub calls: Vec<String>,
+        /// The address and length of every mapping handed out.
+        pub mappings: Vec<(usize, usize)>,
+        /// The index and user pointer of every USERPTR buffer queued.
+        pub userptrs: Vec<(u32, usize)>,
+    }
+
+    impl FakeLog {
+        /// How many times `name` was called, failed calls included.
+        fn count(&self, name: &str) -> usize {
+            self.calls.iter().filter(|call| call.split(' ').next() == Some(name)).count()
+        }
+    }
+
+    /// A single-planar capture device that does what it is told: it applies whatever format is
+    /// set, grants the buffers asked for, and fails the calls scripted with
+    /// [`FakeDevice::fail`]. It only captures the frames scripted with [`FakeDevice::deliver`].
+    /// Buffer memory is anonymous, so it can be unmapped like the real
+    /// thing. Its sensor is [`FakeDevice::SENSOR`], and cropping it shrinks the frame, as on a
+    /// device without a scaler, which is refused with EBUSY while buffers are allocated.
+    pub struct FakeDevice {
+        null: File,
+        device_caps: u32,
+        format: Mutex<v4l2_pix_format>,
+        crop: Mutex<v4l2_rect>,
+        buffer_length: u32,
+        /// How many buffers the last successful `VIDIOC_REQBUFS` granted.
+        allocated: Mutex<u32>,
+        /// Filled buffers for `VIDIOC_DQBUF` to hand out in order; after them it fails with
+        /// EAGAIN, like a non-blocking device that has nothing captured.
+        frames: Mutex<VecDeque<Delivery>>,
+        /// The menu entries `VIDIOC_QUERYMENU` knows; it rejects any other with EINVAL.
+        menu: Vec<v4l2_querymenu>,
+        /// Calls to fail, as the ioctl name, which call of it, and the errno to fail with.
+        failures: Vec<(&'static str, usize, Errno)>,
+        log: Arc<Mutex<FakeLog>>,
+    }
+
+    /// What `VIDIOC_DQBUF` reports about a scripted frame. Kept apart from the `v4l2_buffer` it
+    /// came in, whose pointers would keep the device from being `Send`.
+    struct Delivery {
+        index: u32,
+        bytesused: u32,
+        sequence: u32,
+        timestamp: timeval,
+        flags: u32,
+    }
+
+    impl FakeDevice {
+        pub const SENSOR: v4l2_rect = v4l2_rect { left: 0, top: 0, width: 1920, height: 1080 };
+
+        /// A streaming capture device whose buffers hold `buffer_length` bytes.
+        pub fn new(buffer_length: u32) -> Self {
+            FakeDevice {
+                null: File::open("/dev/null").expect("Failed to open /dev/null"),
+                device_caps: V4L2_CAP_VIDEO_CAPTURE | V4L2_CAP_STREAMING,
+                format: Mutex::new(unsafe { zeroed() }),
+                crop: Mutex::new(Self::SENSOR),
+                buffer_length,
+                allocated: Mutex::new(0),
+                frames: Mutex::default(),
+                menu: Vec::new(),
+                failures: Vec::new(),
+                log: Arc::default(),
+            }
+        }
+
+        pub fn with_caps(self, device_caps: u32) -> Self {
+            FakeDevice { device_caps, ..self }
+        }
+
+        /// Fails call number `nth` (from 0) of ioctl `name`, e.g. `VIDIOC_QUERYBUF`, with `errno`.
+        /// `mmap` counts as an ioctl.
+        pub fn fail(mut self, name: &'static str, nth: usize, errno: Errno) -> Self {
+            self.failures.push((name, nth, errno));
+            self
+        }
+
+        /// Has `VIDIOC_DQBUF` hand out `frame` after the frames scripted before it. Its index,
+        /// bytesused, sequence, timestamp and flags are what the dequeued buffer reports.
+        pub fn deliver(self, frame: v4l2_buffer) -> Self {
+            let v4l2_buffer { index, bytesused, sequence, timestamp, flags, .. } = frame;
+            self.frames.lock().unwrap().push_back(Delivery { index, bytesused, sequence, timestamp, flags });
+            self
+        }
+
+        /// Adds an entry for `VIDIOC_QUERYMENU` to find by its control id and index.
+        pub fn with_menu_item(mut self, item: v4l2_querymenu) -> Self {
+            self.menu.push(item);
+            self
+        }
+
+        pub fn log(&self) -> Arc<Mutex<FakeLog>> {
+            Arc::clone(&self.log)
+        }
+
+        /// Records a call and returns whether it was scripted to fail.
+        fn call(&self, name: &'static str, details: String) -> nix::Result<()> {
+            let mut log = self.log.lock().unwrap();
+            let nth = log.count(name);
+            log.calls.push(if details.is_empty() { name.to_string() } else { format!("{} {}", name, details) });
+            match self.failures.iter().find(|(failing, n, _)| *failing == name && *n == nth) {
+                Some((_, _, errno)) => Err(*errno),
+                None => Ok(()),
+            }
+        }
+    }
+
+    impl AsFd for FakeDevice {
+        fn as_fd(&self) -> BorrowedFd<'_> {
+            self.null.as_fd()
+        }
+    }
+
+    impl VideoDevice for FakeDevice {
+        fn query_capabilities(&self, capabilities: &mut v4l2_capability) -> nix::Result<()> {
+            self.call("VIDIOC_QUERYCAP", String::new())?;
+            capabilities.capabilities = self.device_caps;
+            capabilities.driver[..4].copy_from_slice(b"fake");
+            Ok(())
+        }
+
+        fn enum_formats(&self, _desc: &mut v4l2_fmtdesc) -> nix::Result<()> {
+            self.call("VIDIOC_ENUM_FMT", String::new())?;
+            Err(Errno::EINVAL)
+        }
+
+        fn get_format(&self, format: &mut v4l2_format) -> nix::Result<()> {
+            self.call("VIDIOC_G_FMT", String::new())?;
+            format.fmt.pix = *self.format.lock().unwrap();
+            Ok(())
+        }
+
+        fn set_format(&self, format: &mut v4l2_format) -> nix::Result<()> {
+            let mut pix = unsafe { format.fmt.pix };
+            self.call("VIDIOC_S_FMT", format!("{}x{}", pix.width, pix.height))?;
+            pix.bytesperline = pix.width * 2;
+            pix.sizeimage = pix.bytesperline * pix.height;
+            format.fmt.pix = pix;
+            *self.format.lock().unwrap() = pix;
+            Ok(())
+        }
+
+        fn request_buffers(&self, reqbufs: &mut v4l2_requestbuffers) -> nix::Result<()> {
+            self.call("VIDIOC_REQBUFS", reqbufs.count.to_string())?;
+            *self.allocated.lock().unwrap() = reqbufs.count;
+            Ok(())
+        }
+
+        fn query_buffer(&self, buffer: &mut v4l2_buffer) -> nix::Result<()> {
+            self.call("VIDIOC_QUERYBUF", buffer.index.to_string())?;
+            buffer.length = self.buffer_length;
+            buffer.m.offset = buffer.index * self.buffer_length;
+            Ok(())
+        }
+
+        fn queue_buffer(&self, buffer: &mut v4l2_buffer) -> nix::Result<()> {
+            self.call("VIDIOC_QBUF", buffer.index.to_string())?;
+            if buffer.memory == v4l2_memory_V4L2_MEMORY_USERPTR {
+                let userptr = unsafe { buffer.m.userptr } as usize;
+                self.log.lock().unwrap().userptrs.push((buffer.index, userptr));
+            }
+            Ok(())
+        }
+
+        fn export_buffer(&self, export: &mut v4l2_exportbuffer) -> nix::Result<()> {
+            self.call("VIDIOC_EXPBUF", export.index.to_string())?;
+            Err(Errno::ENOTTY)
+        }
+
+        fn dequeue_buffer(&self, buffer: &mut v4l2_buffer) -> nix::Result<()> {
+            self.call("VIDIOC_DQBUF", String::new())?;
+            let frame = self.frames.lock().unwrap().pop_front().ok_or(Errno::EAGAIN)?;
+            buffer.index = frame.index;
+            buffer.bytesused = frame.bytesused;
+            buffer.sequence = frame.sequence;
+            buffer.timestamp = frame.timestamp;
+            buffer.flags = frame.flags;
+            Ok(())
+        }
+
+        fn stream_on(&self, _buf_type: v4l2_buf_type) -> nix::Result<()> {
+            self.call("VIDIOC_STREAMON", String::new())
+        }
+
+        fn stream_off(&self, _buf_type: v4l2_buf_type) -> nix::Result<()> {
+            self.call("VIDIOC_STREAMOFF", String::new())
+        }
+
+        fn get_stream_params(&self, _params: &mut v4l2_streamparm) -> nix::Result<()> {
+            self.call("VIDIOC_G_PARM", String::new())
+        }
+
+        fn set_stream_params(&self, _params: &mut v4l2_streamparm) -> nix::Result<()> {
+            self.call("VIDIOC_S_PARM", String::new())
+        }
+
+        fn get_ctrl(&self, _control: &mut v4l2_control) -> nix::Result<()> {
+            self.call("VIDIOC_G_CTRL", String::new())
+        }
+
+        fn set_ctrl(&self, _control: &mut v4l2_control) -> nix::Result<()> {
+            self.call("VIDIOC_S_CTRL", String::new())
+        }
+
+        fn query_control(&self, _query: &mut v4l2_queryctrl) -> nix::Result<()> {
+            self.call("VIDIOC_QUERYCTRL", String::new())?;
+            Err(Errno::EINVAL)
+        }
+
+        fn query_ext_control(&self, _query: &mut v4l2_query_ext_ctrl) -> nix::Result<()> {
+            self.call("VIDIOC_QUERY_EXT_CTRL", String::new())?;
+            Err(Errno::EINVAL)
+        }
+
+        fn query_menu(&self, menu: &mut v4l2_querymenu) -> nix::Result<()> {
+            // The struct is packed, so its fields are copied out rather than borrowed.
+            let (id, index) = (menu.id, menu.index);
+            self.call("VIDIOC_QUERYMENU", index.to_string())?;
+            let item = self.menu.iter().find(|item| (item.id, item.index) == (id, index)).ok_or(Errno::EINVAL)?;
+            *menu = *item;
+            Ok(())
+        }
+
+        fn get_ext_controls(&self, _controls: &mut v4l2_ext_controls) -> nix::Result<()> {
+            self.call("VIDIOC_G_EXT_CTRLS", String::new())
+        }
+
+        fn set_ext_controls(&self, _controls: &mut v4l2_ext_controls) -> nix::Result<()> {
+            self.call("VIDIOC_S_EXT_CTRLS", String::new())
+        }
+
+        fn enum_frame_sizes(&self, _frmsize: &mut v4l2_frmsizeenum) -> nix::Result<()> {
+            self.call("VIDIOC_ENUM_FRAMESIZES", String::new())?;
+            Err(Errno::EINVAL)
+        }
+
+        fn enum_frame_intervals(&self, _frmival: &mut v4l2_frmivalenum) -> nix::Result<()> {
+            self.call("VIDIOC_ENUM_FRAMEINTERVALS", String::new())?;
+            Err(Errno::EINVAL)
+        }
+
+        fn get_selection(&self, selection: &mut v4l2_selection) -> nix::Result<()> {
+            self.call("VIDIOC_G_SELECTION", selection.target.to_string())?;
+            selection.r = match selection.target {
+                V4L2_SEL_TGT_CROP => *self.crop.lock().unwrap(),
+                V4L2_SEL_TGT_CROP_BOUNDS | V4L2_SEL_TGT_CROP_DEFAULT => Self::SENSOR,
+                _ => return Err(Errno::EINVAL),
+            };
+            Ok(())
+        }
+
+        fn set_selection(&self, selection: &mut v4l2_selection) -> nix::Result<()> {
+            let r = selection.r;
+            self.call("VIDIOC_S_SELECTION", format!("{}x{}+{}+{}", r.width, r.height, r.left, r.top))?;
+            if selection.target != V4L2_SEL_TGT_CROP {
+                return Err(Errno::EINVAL);
+            }
+            // Like many sensors, crop in whole 2x2 blocks and stay on the sensor.
+            let width = (r.width & !1).clamp(2, Self::SENSOR.width);
+            let height = (r.height & !1).clamp(2, Self::SENSOR.height);
+            let left = (r.left.max(0) as u32 & !1).min(Self::SENSOR.width - width);
+            let top = (r.top.max(0) as u32 & !1).min(Self::SENSOR.height - height);
+            let mut format = self.format.lock().unwrap();
+            // The frame size follows the crop, and buffers already allocated could not hold it.
+            if (width, height) != (format.width, format.height) && *self.allocated.lock().unwrap() > 0 {
+                return Err(Errno::EBUSY);
+            }
+            selection.r = v4l2_rect { left: left as i32, top: top as i32, width, height };
+            *self.crop.lock().unwrap() = selection.r;
+
+            format.width = width;
+            format.height = height;
+            format.bytesperline = width * 2;
+            format.sizeimage = width * height * 2;
+            Ok(())
+        }
+
+        fn map(&self, length: NonZeroUsize, offset: u32) -> nix::Result<NonNull<c_void>> {
+            self.call("mmap", offset.to_string())?;
+            let start = unsafe { mmap_anonymous(None, length, ProtFlags::PROT_READ | ProtFlags::PROT_WRITE, MapFlags::MAP_PRIVATE) }?;
+            self.log.lock().unwrap().mappings.push((start.as_ptr() as usize, length.get()));
+            Ok(start)
+        }
+    }
+}
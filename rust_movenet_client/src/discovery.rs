@@ -0,0 +1,216 @@
+use crate::device::VideoDevice;
+use crate::format::{self, FormatDescription};
+use std::fs::{self, OpenOptions};
+use std::io::Error;
+use std::mem::zeroed;
+use v4l2_sys_mit::*;
+
+/// Capability bits worth showing when listing devices, with how they are printed.
+const CAPABILITY_NAMES: [(u32, &str); 9] = [
+    (V4L2_CAP_VIDEO_CAPTURE, "video capture"),
+    (V4L2_CAP_VIDEO_CAPTURE_MPLANE, "multi-planar video capture"),
+    (V4L2_CAP_VIDEO_OUTPUT, "video output"),
+    (V4L2_CAP_VIDEO_OUTPUT_MPLANE, "multi-planar video output"),
+    (V4L2_CAP_VIDEO_M2M, "memory-to-memory"),
+    (V4L2_CAP_VIDEO_M2M_MPLANE, "multi-planar memory-to-memory"),
+    (V4L2_CAP_META_CAPTURE, "metadata capture"),
+    (V4L2_CAP_READWRITE, "read/write"),
+    (V4L2_CAP_STREAMING, "streaming"),
+];
+
+/// What `VIDIOC_QUERYCAP` reports about a device node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    pub driver: String,
+    pub card: String,
+    pub bus_info: String,
+    /// Capabilities of this node, as opposed to every node of the physical device.
+    pub device_caps: u32,
+}
+
+impl Capabilities {
+    pub fn query(device: &dyn VideoDevice) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut capabilities = v4l2_capability { ..unsafe { zeroed() } };
+        device.query_capabilities(&mut capabilities).map_err(Error::from)?;
+
+        // Drivers that predate per-node capabilities only fill in `capabilities`.
+        let device_caps = if capabilities.capabilities & V4L2_CAP_DEVICE_CAPS != 0 {
+            capabilities.device_caps
+        } else {
+            capabilities.capabilities
+        };
+        Ok(Capabilities {
+            driver: c_string(&capabilities.driver),
+            card: c_string(&capabilities.card),
+            bus_info: c_string(&capabilities.bus_info),
+            device_caps,
+        })
+    }
+
+    /// The buffer type to capture with: single-planar when the node supports it, and
+    /// `V4L2_BUF_TYPE_VIDEO_CAPTURE_MPLANE` for nodes, typically embedded ISPs, that only
+    /// support the multi-planar API. `None` if the node cannot capture video at all.
+    pub fn capture_buf_type(&self) -> Option<v4l2_buf_type> {
+        if self.device_caps & V4L2_CAP_VIDEO_CAPTURE != 0 {
+            Some(v4l2_buf_type_V4L2_BUF_TYPE_VIDEO_CAPTURE)
+        } else if self.device_caps & V4L2_CAP_VIDEO_CAPTURE_MPLANE != 0 {
+            Some(v4l2_buf_type_V4L2_BUF_TYPE_VIDEO_CAPTURE_MPLANE)
+        } else {
+            None
+        }
+    }
+
+    /// The names of the capabilities this node has, e.g. `video capture, streaming`.
+    pub fn names(&self) -> Vec<&'static str> {
+        CAPABILITY_NAMES
+            .iter()
+            .filter(|(bit, _)| self.device_caps & bit != 0)
+            .map(|(_, name)| *name)
+            .collect()
+    }
+}
+
+/// Runs `VIDIOC_QUERYCAP` on `device` and picks the buffer type to capture with.
+pub fn capture_buf_type(device: &dyn VideoDevice) -> Result<v4l2_buf_type, Box<dyn std::error::Error>> {
+    let capabilities = Capabilities::query(device)?;
+    capabilities.capture_buf_type().ok_or_else(|| Error::other("Device does not support video capture").into())
+}
+
+/// A V4L2 device node and what it can do.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Device {
+    pub path: String,
+    pub capabilities: Capabilities,
+    /// The formats the node captures in; empty for nodes that cannot capture video.
+    pub formats: Vec<FormatDescription>,
+}
+
+impl Device {
+    pub fn open(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let fd = OpenOptions::new().read(true).write(true).open(path)?;
+        let capabilities = Capabilities::query(&fd)?;
+        let formats = match capabilities.capture_buf_type() {
+            Some(buf_type) => format::enumerate_formats(&fd, buf_type)?,
+            None => Vec::new(),
+        };
+        Ok(Device { path: path.to_string(), capabilities, formats })
+    }
+
+    /// Whether the client can stream video from this node.
+    pub fn can_stream(&self) -> bool {
+        self.capabilities.capture_buf_type().is_some()
+            && self.capabilities.device_caps & V4L2_CAP_STREAMING != 0
+            && !self.formats.is_empty()
+    }
+}
+
+/// Finds the `/dev/video*` nodes, in numeric order.
+pub fn video_nodes() -> Vec<String> {
+    let mut nodes: Vec<(u32, String)> = match fs::read_dir("/dev") {
+        Ok(entries) => entries
+            .filter_map(|entry| {
+                let name = entry.ok()?.file_name().into_string().ok()?;
+                Some((video_index(&name)?, format!("/dev/{}", name)))
+            })
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    nodes.sort();
+    nodes.into_iter().map(|(_, path)| path).collect()
+}
+
+/// The number of a `videoN` node name.
+fn video_index(name: &str) -> Option<u32> {
+    let digits = name.strip_prefix("video")?;
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse().ok()
+}
+
+/// Picks the first node the client can stream from, preferring one that offers `fourcc`.
+/// Metadata nodes and ISP inputs, which often come first, are skipped.
+pub fn pick_device(fourcc: u32) -> Result<Device, Box<dyn std::error::Error>> {
+    let candidates: Vec<Device> = video_nodes()
+        .iter()
+        .filter_map(|path| Device::open(path).ok())
+        .filter(Device::can_stream)
+        .collect();
+    let offers_fourcc = |device: &Device| device.formats.iter().any(|f| f.fourcc == fourcc);
+    let preferred = candidates.iter().position(offers_fourcc).unwrap_or(0);
+    candidates.into_iter().nth(preferred).ok_or_else(|| "No V4L2 capture device found".into())
+}
+
+/// `requested` if a device was given, otherwise the node [`pick_device`] finds.
+pub fn device_or_pick(requested: Option<&str>, fourcc: u32) -> Result<String, Box<dyn std::error::Error>> {
+    if let Some(device) = requested {
+        return Ok(device.to_string());
+    }
+    let device = pick_device(fourcc)?;
+    println!("Using {} ({})", device.path, device.capabilities.card);
+    Ok(device.path)
+}
+
+/// Prints every `/dev/video*` node with its capabilities, and for capture nodes their
+/// formats, frame sizes and frame rates.
+pub fn print_devices() -> Result<(), Box<dyn std::error::Error>> {
+    for path in video_nodes() {
+        // Nodes we may not open, for lack of permission say, are still worth listing.
+        let device = match Device::open(&path) {
+            Ok(device) => device,
+            Err(e) => {
+                println!("{}: {}", path, e);
+                continue;
+            }
+        };
+        let caps = &device.capabilities;
+        println!("{}: {} ({}, {})", device.path, caps.card, caps.driver, caps.bus_info);
+        println!("    {}", caps.names().join(", "));
+        if let Some(buf_type) = caps.capture_buf_type() {
+            let fd = OpenOptions::new().read(true).write(true).open(&device.path)?;
+            format::print_format_list(&fd, buf_type, "    ")?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads a NUL-padded string out of a V4L2 struct.
+fn c_string(bytes: &[u8]) -> String {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..len]).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_numbered_video_nodes_are_scanned() {
+        assert_eq!(video_index("video0"), Some(0));
+        assert_eq!(video_index("video12"), Some(12));
+        assert_eq!(video_index("video"), None);
+        assert_eq!(video_index("video-codec"), None);
+        assert_eq!(video_index("media0"), None);
+    }
+
+    #[test]
+    fn capabilities_pick_a_buffer_type() {
+        let caps = |device_caps| Capabilities { driver: String::new(), card: String::new(), bus_info: String::new(), device_caps };
+
+        let webcam = caps(V4L2_CAP_VIDEO_CAPTURE | V4L2_CAP_STREAMING);
+        assert_eq!(webcam.capture_buf_type(), Some(v4l2_buf_type_V4L2_BUF_TYPE_VIDEO_CAPTURE));
+        assert_eq!(webcam.names(), ["video capture", "streaming"]);
+
+        let isp = caps(V4L2_CAP_VIDEO_CAPTURE_MPLANE | V4L2_CAP_STREAMING);
+        assert_eq!(isp.capture_buf_type(), Some(v4l2_buf_type_V4L2_BUF_TYPE_VIDEO_CAPTURE_MPLANE));
+
+        let metadata = caps(V4L2_CAP_META_CAPTURE | V4L2_CAP_STREAMING);
+        assert_eq!(metadata.capture_buf_type(), None);
+    }
+
+    #[test]
+    fn c_strings_stop_at_the_first_nul() {
+        assert_eq!(c_string(b"uvcvideo\0\0\0"), "uvcvideo");
+        assert_eq!(c_string(b"full"), "full");
+    }
+}
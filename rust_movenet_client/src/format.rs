@@ -0,0 +1,516 @@
+use crate::device::VideoDevice;
+use crate::discovery;
+use nix::errno::Errno;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::Error;
+use std::mem::zeroed;
+use std::str::FromStr;
+use v4l2_sys_mit::*;
+
+/// Builds a V4L2 four-character code, the same way the kernel's `v4l2_fourcc` macro does.
+pub const fn fourcc(code: &[u8; 4]) -> u32 {
+    (code[0] as u32) | (code[1] as u32) << 8 | (code[2] as u32) << 16 | (code[3] as u32) << 24
+}
+
+pub const V4L2_PIX_FMT_YUYV: u32 = fourcc(b"YUYV");
+pub const V4L2_PIX_FMT_NV12: u32 = fourcc(b"NV12");
+/// NV12 with the Y and UV planes in separate buffers, as multi-planar devices deliver it.
+pub const V4L2_PIX_FMT_NV12M: u32 = fourcc(b"NM12");
+pub const V4L2_PIX_FMT_MJPEG: u32 = fourcc(b"MJPG");
+
+/// Renders a four-character code for log messages, e.g. `YUYV`.
+pub struct FourCc(pub u32);
+
+impl fmt::Display for FourCc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes = self.0.to_le_bytes();
+        write!(f, "{}", String::from_utf8_lossy(&bytes).trim_end())
+    }
+}
+
+/// The capture format the driver applied.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PixFormat {
+    pub width: u32,
+    pub height: u32,
+    pub fourcc: u32,
+    /// Length of one row of the first plane in bytes, including any padding the driver adds.
+    pub bytes_per_line: u32,
+    /// Size of a whole frame in bytes, over all planes.
+    pub size_image: u32,
+    /// Number of separately allocated memory planes. Single-planar formats, and multi-planar
+    /// ones packed into one buffer like NV12, have one.
+    pub num_planes: u32,
+    /// Whether the rows of every plane are `bytes_per_line` long. Frames are sent with that
+    /// one stride, so a chroma plane padded differently from the luma plane cannot be.
+    pub uniform_stride: bool,
+}
+
+impl From<&v4l2_pix_format> for PixFormat {
+    fn from(pix: &v4l2_pix_format) -> Self {
+        PixFormat {
+            width: pix.width,
+            height: pix.height,
+            fourcc: pix.pixelformat,
+            bytes_per_line: pix.bytesperline,
+            size_image: pix.sizeimage,
+            num_planes: 1,
+            uniform_stride: true,
+        }
+    }
+}
+
+impl From<&v4l2_pix_format_mplane> for PixFormat {
+    fn from(pix: &v4l2_pix_format_mplane) -> Self {
+        let planes = &pix.plane_fmt[..pix.num_planes as usize];
+        PixFormat {
+            width: pix.width,
+            height: pix.height,
+            fourcc: pix.pixelformat,
+            bytes_per_line: planes.first().map_or(0, |plane| plane.bytesperline),
+            size_image: planes.iter().map(|plane| plane.sizeimage).sum(),
+            num_planes: pix.num_planes as u32,
+            uniform_stride: planes.windows(2).all(|pair| pair[0].bytesperline == pair[1].bytesperline),
+        }
+    }
+}
+
+impl fmt::Display for PixFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}x{} {} ({} bytes per line", self.width, self.height, FourCc(self.fourcc), self.bytes_per_line)?;
+        if self.num_planes > 1 {
+            write!(f, ", {} planes", self.num_planes)?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// One entry of `VIDIOC_ENUM_FMT`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FormatDescription {
+    pub fourcc: u32,
+    pub description: String,
+    pub compressed: bool,
+}
+
+/// Frame sizes a device offers for one pixel format.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameSizes {
+    Discrete(Vec<(u32, u32)>),
+    /// Any size between `min` and `max` in `step` increments. Continuous ranges have a step of 1.
+    Stepwise { min: (u32, u32), max: (u32, u32), step: (u32, u32) },
+}
+
+impl fmt::Display for FrameSizes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameSizes::Discrete(sizes) => {
+                let sizes: Vec<String> = sizes.iter().map(|(w, h)| format!("{}x{}", w, h)).collect();
+                write!(f, "{}", sizes.join(", "))
+            }
+            FrameSizes::Stepwise { min, max, step } => {
+                write!(f, "{}x{} to {}x{} in steps of {}x{}", min.0, min.1, max.0, max.1, step.0, step.1)
+            }
+        }
+    }
+}
+
+/// Time between frames in seconds, as the fraction `numerator / denominator` V4L2 uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameInterval {
+    pub numerator: u32,
+    pub denominator: u32,
+}
+
+impl FrameInterval {
+    pub fn from_fps(fps: u32) -> Self {
+        FrameInterval { numerator: 1, denominator: fps }
+    }
+
+    pub fn fps(&self) -> f64 {
+        if self.numerator == 0 {
+            0.0
+        } else {
+            self.denominator as f64 / self.numerator as f64
+        }
+    }
+}
+
+impl From<v4l2_fract> for FrameInterval {
+    fn from(fract: v4l2_fract) -> Self {
+        FrameInterval { numerator: fract.numerator, denominator: fract.denominator }
+    }
+}
+
+impl fmt::Display for FrameInterval {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.2} FPS", self.fps())
+    }
+}
+
+/// Frame intervals a device offers for one format and frame size.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameIntervals {
+    Discrete(Vec<FrameInterval>),
+    /// Any interval between `min` and `max` in `step` increments.
+    Stepwise { min: FrameInterval, max: FrameInterval, step: FrameInterval },
+}
+
+impl fmt::Display for FrameIntervals {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameIntervals::Discrete(intervals) => {
+                let intervals: Vec<String> = intervals.iter().map(|i| format!("{:.2}", i.fps())).collect();
+                write!(f, "{} FPS", intervals.join(", "))
+            }
+            // The longest interval is the lowest rate.
+            FrameIntervals::Stepwise { min, max, step } => {
+                write!(f, "{:.2} to {:.2} FPS in interval steps of {}/{} s", max.fps(), min.fps(), step.numerator, step.denominator)
+            }
+        }
+    }
+}
+
+/// A rectangle in pixels, written `WIDTHxHEIGHT+LEFT+TOP` like an X11 geometry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub left: u32,
+    pub top: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    /// The whole of a `width`x`height` frame.
+    pub fn full(width: u32, height: u32) -> Self {
+        Rect { left: 0, top: 0, width, height }
+    }
+}
+
+impl From<v4l2_rect> for Rect {
+    fn from(rect: v4l2_rect) -> Self {
+        // Some sensors put the origin inside their bounds; nothing the client asks for lies
+        // to the left of or above it.
+        Rect { left: rect.left.max(0) as u32, top: rect.top.max(0) as u32, width: rect.width, height: rect.height }
+    }
+}
+
+impl From<Rect> for v4l2_rect {
+    fn from(rect: Rect) -> Self {
+        v4l2_rect { left: rect.left as i32, top: rect.top as i32, width: rect.width, height: rect.height }
+    }
+}
+
+impl fmt::Display for Rect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}x{}+{}+{}", self.width, self.height, self.left, self.top)
+    }
+}
+
+impl FromStr for Rect {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("expected WIDTHxHEIGHT+LEFT+TOP, got {:?}", s);
+        let (size, offset) = s.split_once('+').unwrap_or((s, "0+0"));
+        let (width, height) = size.split_once('x').ok_or_else(invalid)?;
+        let (left, top) = offset.split_once('+').ok_or_else(invalid)?;
+        let number = |n: &str| n.trim().parse::<u32>().map_err(|_| invalid());
+        let rect = Rect { left: number(left)?, top: number(top)?, width: number(width)?, height: number(height)? };
+        if rect.width == 0 || rect.height == 0 {
+            return Err(format!("{:?} is empty", s));
+        }
+        Ok(rect)
+    }
+}
+
+fn is_multiplanar(buf_type: v4l2_buf_type) -> bool {
+    buf_type == v4l2_buf_type_V4L2_BUF_TYPE_VIDEO_CAPTURE_MPLANE
+}
+
+/// Lists the capture formats the device supports for `buf_type`.
+pub fn enumerate_formats(device: &dyn VideoDevice, buf_type: v4l2_buf_type) -> Result<Vec<FormatDescription>, Box<dyn std::error::Error>> {
+    let mut formats = Vec::new();
+    for index in 0.. {
+        let mut desc = v4l2_fmtdesc {
+            index,
+            type_: buf_type,
+            ..unsafe { zeroed() }
+        };
+        match device.enum_formats(&mut desc) {
+            Ok(_) => {}
+            // EINVAL marks the end of the list.
+            Err(Errno::EINVAL) => break,
+            Err(e) => return Err(Box::new(Error::from(e))),
+        }
+
+        let name_len = desc.description.iter().position(|&b| b == 0).unwrap_or(desc.description.len());
+        formats.push(FormatDescription {
+            fourcc: desc.pixelformat,
+            description: String::from_utf8_lossy(&desc.description[..name_len]).into_owned(),
+            compressed: desc.flags & V4L2_FMT_FLAG_COMPRESSED != 0,
+        });
+    }
+    Ok(formats)
+}
+
+/// Lists the frame sizes the device supports for `fourcc`.
+pub fn enumerate_frame_sizes(device: &dyn VideoDevice, fourcc: u32) -> Result<FrameSizes, Box<dyn std::error::Error>> {
+    let mut sizes = Vec::new();
+    for index in 0.. {
+        let mut frmsize = v4l2_frmsizeenum {
+            index,
+            pixel_format: fourcc,
+            ..unsafe { zeroed() }
+        };
+        match device.enum_frame_sizes(&mut frmsize) {
+            Ok(_) => {}
+            Err(Errno::EINVAL) => break,
+            Err(e) => return Err(Box::new(Error::from(e))),
+        }
+
+        if frmsize.type_ == v4l2_frmsizetypes_V4L2_FRMSIZE_TYPE_DISCRETE {
+            let discrete = unsafe { frmsize.__bindgen_anon_1.discrete };
+            sizes.push((discrete.width, discrete.height));
+        } else {
+            // Stepwise and continuous ranges are reported as a single entry.
+            let stepwise = unsafe { frmsize.__bindgen_anon_1.stepwise };
+            return Ok(FrameSizes::Stepwise {
+                min: (stepwise.min_width, stepwise.min_height),
+                max: (stepwise.max_width, stepwise.max_height),
+                step: (stepwise.step_width, stepwise.step_height),
+            });
+        }
+    }
+    Ok(FrameSizes::Discrete(sizes))
+}
+
+/// Lists the frame intervals the device supports for `fourcc` at `width`x`height`.
+pub fn enumerate_frame_intervals(device: &dyn VideoDevice, fourcc: u32, width: u32, height: u32) -> Result<FrameIntervals, Box<dyn std::error::Error>> {
+    let mut intervals = Vec::new();
+    for index in 0.. {
+        let mut frmival = v4l2_frmivalenum {
+            index,
+            pixel_format: fourcc,
+            width,
+            height,
+            ..unsafe { zeroed() }
+        };
+        match device.enum_frame_intervals(&mut frmival) {
+            Ok(_) => {}
+            Err(Errno::EINVAL) => break,
+            Err(e) => return Err(Box::new(Error::from(e))),
+        }
+
+        if frmival.type_ == v4l2_frmivaltypes_V4L2_FRMIVAL_TYPE_DISCRETE {
+            intervals.push(FrameInterval::from(unsafe { frmival.__bindgen_anon_1.discrete }));
+        } else {
+            let stepwise = unsafe { frmival.__bindgen_anon_1.stepwise };
+            return Ok(FrameIntervals::Stepwise {
+                min: stepwise.min.into(),
+                max: stepwise.max.into(),
+                step: stepwise.step.into(),
+            });
+        }
+    }
+    Ok(FrameIntervals::Discrete(intervals))
+}
+
+fn get_capture_params(device: &dyn VideoDevice, buf_type: v4l2_buf_type) -> Result<v4l2_captureparm, Box<dyn std::error::Error>> {
+    let mut params = v4l2_streamparm {
+        type_: buf_type,
+        ..unsafe { zeroed() }
+    };
+    device.get_stream_params(&mut params).map_err(Error::from)?;
+    Ok(unsafe { params.parm.capture })
+}
+
+/// Reads the current frame interval, or `None` if the driver does not let it be chosen.
+pub fn get_frame_interval(device: &dyn VideoDevice, buf_type: v4l2_buf_type) -> Result<Option<FrameInterval>, Box<dyn std::error::Error>> {
+    let capture = get_capture_params(device, buf_type)?;
+    if capture.capability & V4L2_CAP_TIMEPERFRAME == 0 {
+        return Ok(None);
+    }
+    Ok(Some(capture.timeperframe.into()))
+}
+
+/// Asks the driver for `interval` between frames and returns the interval it applied, which
+/// is the closest one it supports.
+pub fn set_frame_interval(device: &dyn VideoDevice, buf_type: v4l2_buf_type, interval: FrameInterval) -> Result<FrameInterval, Box<dyn std::error::Error>> {
+    if get_capture_params(device, buf_type)?.capability & V4L2_CAP_TIMEPERFRAME == 0 {
+        return Err(Box::new(Error::other("Device does not support setting the frame rate")));
+    }
+
+    let mut params = v4l2_streamparm {
+        type_: buf_type,
+        ..unsafe { zeroed() }
+    };
+    params.parm.capture = v4l2_captureparm {
+        timeperframe: v4l2_fract { numerator: interval.numerator, denominator: interval.denominator },
+        ..unsafe { zeroed() }
+    };
+    device.set_stream_params(&mut params).map_err(Error::from)?;
+    Ok(unsafe { params.parm.capture.timeperframe }.into())
+}
+
+/// Reads the current capture format.
+pub fn get_pix_format(device: &dyn VideoDevice, buf_type: v4l2_buf_type) -> Result<PixFormat, Box<dyn std::error::Error>> {
+    let mut format = v4l2_format {
+        type_: buf_type,
+        ..unsafe { zeroed() }
+    };
+    device.get_format(&mut format).map_err(Error::from)?;
+    if is_multiplanar(buf_type) {
+        Ok(PixFormat::from(unsafe { &format.fmt.pix_mp }))
+    } else {
+        Ok(PixFormat::from(unsafe { &format.fmt.pix }))
+    }
+}
+
+/// Asks the driver for `width`x`height` frames in `fourcc`. The driver picks the closest
+/// format it supports, so callers have to check the returned format rather than assume.
+pub fn set_pix_format(device: &dyn VideoDevice, buf_type: v4l2_buf_type, width: u32, height: u32, fourcc: u32) -> Result<PixFormat, Box<dyn std::error::Error>> {
+    let mut format = v4l2_format {
+        type_: buf_type,
+        ..unsafe { zeroed() }
+    };
+    if is_multiplanar(buf_type) {
+        // The driver fills in the plane count and layout for the format.
+        format.fmt.pix_mp = v4l2_pix_format_mplane {
+            width,
+            height,
+            pixelformat: fourcc,
+            field: v4l2_field_V4L2_FIELD_ANY,
+            ..unsafe { zeroed() }
+        };
+    } else {
+        format.fmt.pix = v4l2_pix_format {
+            width,
+            height,
+            pixelformat: fourcc,
+            field: v4l2_field_V4L2_FIELD_ANY,
+            ..unsafe { zeroed() }
+        };
+    }
+    device.set_format(&mut format).map_err(Error::from)?;
+    get_pix_format(device, buf_type)
+}
+
+/// Reads one selection rectangle, e.g. `V4L2_SEL_TGT_CROP_BOUNDS` for the area the sensor can
+/// be cropped to. Devices that cannot crop fail with `ENOTTY` or `EINVAL`.
+pub fn get_selection(device: &dyn VideoDevice, buf_type: v4l2_buf_type, target: u32) -> Result<Rect, Box<dyn std::error::Error>> {
+    let mut selection = v4l2_selection {
+        type_: buf_type,
+        target,
+        ..unsafe { zeroed() }
+    };
+    device.get_selection(&mut selection).map_err(Error::from)?;
+    Ok(selection.r.into())
+}
+
+/// Sets a selection rectangle. The driver adjusts it to what the hardware supports, so the
+/// rectangle it applied is returned.
+pub fn set_selection(device: &dyn VideoDevice, buf_type: v4l2_buf_type, target: u32, rect: Rect) -> Result<Rect, Box<dyn std::error::Error>> {
+    let mut selection = v4l2_selection {
+        type_: buf_type,
+        target,
+        r: rect.into(),
+        ..unsafe { zeroed() }
+    };
+    device.set_selection(&mut selection).map_err(Error::from)?;
+    Ok(selection.r.into())
+}
+
+/// Prints every format `device_path` supports along with its frame sizes, and the sensor crop
+/// if the device can crop.
+pub fn print_formats(device_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let fd = OpenOptions::new().read(true).write(true).open(device_path)?;
+    let buf_type = discovery::capture_buf_type(&fd)?;
+    if is_multiplanar(buf_type) {
+        println!("Multi-planar capture device");
+    }
+    print_format_list(&fd, buf_type, "")?;
+    if let (Ok(crop), Ok(bounds)) = (get_selection(&fd, buf_type, V4L2_SEL_TGT_CROP), get_selection(&fd, buf_type, V4L2_SEL_TGT_CROP_BOUNDS)) {
+        println!("Crop: {} (bounds {})", crop, bounds);
+    }
+    Ok(())
+}
+
+/// Prints the formats of an open device, each line starting with `indent`.
+pub fn print_format_list(device: &dyn VideoDevice, buf_type: v4l2_buf_type, indent: &str) -> Result<(), Box<dyn std::error::Error>> {
+    for format in enumerate_formats(device, buf_type)? {
+        let compressed = if format.compressed { ", compressed" } else { "" };
+        println!("{}{} ({}{})", indent, FourCc(format.fourcc), format.description, compressed);
+        match enumerate_frame_sizes(device, format.fourcc)? {
+            FrameSizes::Discrete(sizes) => {
+                for (width, height) in sizes {
+                    let intervals = enumerate_frame_intervals(device, format.fourcc, width, height)?;
+                    println!("{}    {}x{}: {}", indent, width, height, intervals);
+                }
+            }
+            sizes => println!("{}    {}", indent, sizes),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fourcc_matches_the_kernel_values() {
+        assert_eq!(V4L2_PIX_FMT_YUYV, 0x5659_5559);
+        assert_eq!(V4L2_PIX_FMT_MJPEG, 0x4750_4a4d);
+        assert_eq!(FourCc(V4L2_PIX_FMT_YUYV).to_string(), "YUYV");
+        assert_eq!(V4L2_PIX_FMT_NV12M, 0x3231_4d4e);
+    }
+
+    #[test]
+    fn multiplanar_format_sums_its_planes() {
+        let mut pix: v4l2_pix_format_mplane = unsafe { zeroed() };
+        pix.width = 640;
+        pix.height = 480;
+        pix.pixelformat = V4L2_PIX_FMT_NV12M;
+        pix.num_planes = 2;
+        pix.plane_fmt[0].bytesperline = 640;
+        pix.plane_fmt[0].sizeimage = 640 * 480;
+        pix.plane_fmt[1].bytesperline = 640;
+        pix.plane_fmt[1].sizeimage = 640 * 240;
+        // Entries past `num_planes` are ignored.
+        pix.plane_fmt[2].sizeimage = 1;
+
+        let format = PixFormat::from(&pix);
+        assert_eq!((format.bytes_per_line, format.size_image, format.num_planes), (640, 640 * 720, 2));
+        assert_eq!(format.to_string(), "640x480 NM12 (640 bytes per line, 2 planes)");
+        assert!(format.uniform_stride);
+
+        // An ISP that pads its chroma rows further than its luma rows.
+        pix.plane_fmt[1].bytesperline = 704;
+        assert!(!PixFormat::from(&pix).uniform_stride);
+    }
+
+    #[test]
+    fn frame_intervals_convert_to_rates() {
+        assert_eq!(FrameInterval::from_fps(10).fps(), 10.0);
+        assert_eq!(FrameInterval { numerator: 1001, denominator: 30000 }.to_string(), "29.97 FPS");
+        let range = FrameIntervals::Stepwise {
+            min: FrameInterval::from_fps(60),
+            max: FrameInterval::from_fps(5),
+            step: FrameInterval { numerator: 1, denominator: 60 },
+        };
+        assert_eq!(range.to_string(), "5.00 to 60.00 FPS in interval steps of 1/60 s");
+    }
+
+    #[test]
+    fn rects_parse_as_geometries() {
+        let rect: Rect = "1280x720+320+180".parse().unwrap();
+        assert_eq!(rect, Rect { left: 320, top: 180, width: 1280, height: 720 });
+        assert_eq!(rect.to_string(), "1280x720+320+180");
+        assert_eq!("640x480".parse(), Ok(Rect::full(640, 480)));
+        assert!("640x480+10".parse::<Rect>().is_err());
+        assert!("0x480".parse::<Rect>().is_err());
+        assert!("640+0+0".parse::<Rect>().is_err());
+    }
+}
@@ -3,9 +3,26 @@ use std::os::raw::c_int;
 use v4l2_sys_mit::*;
 
 ioctl_read!(query_capabilities, b'V', 0, v4l2_capability);
+ioctl_readwrite!(enum_formats, b'V', 2, v4l2_fmtdesc);
+ioctl_readwrite!(get_format, b'V', 4, v4l2_format);
+ioctl_readwrite!(set_format, b'V', 5, v4l2_format);
 ioctl_readwrite!(request_buffers, b'V', 8, v4l2_requestbuffers);
 ioctl_readwrite!(query_buffers, b'V', 9, v4l2_buffer);
 ioctl_readwrite!(q_buffer, b'V', 15, v4l2_buffer);
+ioctl_readwrite!(export_buffer, b'V', 16, v4l2_exportbuffer);
 ioctl_readwrite!(dq_buffer, b'V', 17, v4l2_buffer);
 ioctl_write_ptr!(vidioc_streamon, b'V', 18, c_int);
 ioctl_write_ptr!(vidioc_streamoff, b'V', 19, c_int);
+ioctl_readwrite!(get_stream_params, b'V', 21, v4l2_streamparm);
+ioctl_readwrite!(set_stream_params, b'V', 22, v4l2_streamparm);
+ioctl_readwrite!(get_ctrl, b'V', 27, v4l2_control);
+ioctl_readwrite!(set_ctrl, b'V', 28, v4l2_control);
+ioctl_readwrite!(query_control, b'V', 36, v4l2_queryctrl);
+ioctl_readwrite!(query_menu, b'V', 37, v4l2_querymenu);
+ioctl_readwrite!(get_ext_controls, b'V', 71, v4l2_ext_controls);
+ioctl_readwrite!(set_ext_controls, b'V', 72, v4l2_ext_controls);
+ioctl_readwrite!(enum_frame_sizes, b'V', 74, v4l2_frmsizeenum);
+ioctl_readwrite!(enum_frame_intervals, b'V', 75, v4l2_frmivalenum);
+ioctl_readwrite!(get_selection, b'V', 94, v4l2_selection);
+ioctl_readwrite!(set_selection, b'V', 95, v4l2_selection);
+ioctl_readwrite!(query_ext_control, b'V', 103, v4l2_query_ext_ctrl);
@@ -1,12 +1,37 @@
 mod app;
 mod buffer;
 mod camera;
+mod config;
+mod controls;
+mod crop;
+mod device;
+mod discovery;
+mod format;
 mod ioctl_macros;
+mod overlay;
+mod preprocess;
 mod server_facing;
+mod source;
 
 use app::App;
+use clap::Parser;
+use config::Config;
 
 fn main() {
-    let mut app = App::new("10.66.83.44:7878").expect("Failed to initialize App");
+    let config = Config::parse();
+    if config.list_devices {
+        discovery::print_devices().expect("Failed to list devices");
+        return;
+    }
+    if config.list_formats || config.list_controls {
+        let device = discovery::device_or_pick(config.device.as_deref(), config.format.fourcc()).expect("Failed to find a camera");
+        if config.list_formats {
+            format::print_formats(&device).expect("Failed to list formats");
+        } else {
+            controls::print_controls(&device).expect("Failed to list controls");
+        }
+        return;
+    }
+    let mut app = App::new(&config).expect("Failed to initialize App");
     app.run().expect("App encountered an error");
 }
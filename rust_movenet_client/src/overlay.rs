@@ -0,0 +1,25 @@
+use opencv::core::{Mat, Point, Scalar};
+use opencv::imgproc::{circle, line, LINE_AA};
+use opencv::prelude::*;
+use rust_movenet_protocol::{keypoint_to_pixel, NUM_KEYPOINTS, SKELETON};
+
+/// Draws the skeleton the same way the server does in its annotated JPEG modes.
+pub fn draw_pose(img: &mut Mat, keypoints: &[f32], threshold: f32) -> opencv::Result<()> {
+    let (width, height) = (img.cols() as u32, img.rows() as u32);
+
+    for &(start, end) in &SKELETON {
+        let start_point = keypoint_to_pixel(keypoints, start, width, height, threshold);
+        let end_point = keypoint_to_pixel(keypoints, end, width, height, threshold);
+        if let (Some((start_x, start_y)), Some((end_x, end_y))) = (start_point, end_point) {
+            line(img, Point::new(start_x, start_y), Point::new(end_x, end_y), Scalar::new(0.0, 255.0, 0.0, 0.0), 2, LINE_AA, 0)?;
+        }
+    }
+
+    for index in 0..NUM_KEYPOINTS {
+        if let Some((x, y)) = keypoint_to_pixel(keypoints, index, width, height, threshold) {
+            circle(img, Point::new(x, y), 5, Scalar::new(0.0, 0.0, 255.0, 0.0), -1, LINE_AA, 0)?;
+        }
+    }
+
+    Ok(())
+}
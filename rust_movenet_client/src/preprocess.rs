@@ -0,0 +1,134 @@
+use opencv::core::{flip, rotate, Mat, Scalar, Size, BORDER_CONSTANT, ROTATE_180, ROTATE_90_CLOCKWISE, ROTATE_90_COUNTERCLOCKWISE};
+use opencv::imgcodecs::{imdecode, imread, IMREAD_COLOR};
+use opencv::imgproc::{copy_make_border, cvt_color, resize, COLOR_YUV2BGR_NV12, COLOR_YUV2BGR_YUYV, INTER_LINEAR};
+use opencv::prelude::*;
+use rust_movenet_protocol::{FrameHeader, Letterbox, PixelFormat};
+use std::borrow::Cow;
+use std::path::Path;
+
+/// Converts a captured frame, given as its memory planes, into a BGR image.
+pub fn to_bgr(planes: &[&[u8]], header: &FrameHeader) -> opencv::Result<Mat> {
+    match header.pixel_format {
+        PixelFormat::Nv12 => nv12_to_bgr(&packed(planes, header), header),
+        PixelFormat::Mjpeg => mjpeg_to_bgr(planes[0]),
+        _ => yuyv_to_bgr(planes[0], header),
+    }
+}
+
+/// Lays the planes of a frame out back to back, the way the protocol sends them.
+pub fn pack_planes(planes: &[&[u8]], header: &FrameHeader) -> Vec<u8> {
+    packed(planes, header).into_owned()
+}
+
+/// Borrows single-plane frames as they are. Any padding after the first plane's rows is left
+/// out, so the second plane starts right after `stride * height` bytes as the receiver expects.
+/// Every plane has rows of `stride` bytes; the camera refuses formats where they differ.
+fn packed<'a>(planes: &[&'a [u8]], header: &FrameHeader) -> Cow<'a, [u8]> {
+    match planes {
+        [single] => Cow::Borrowed(*single),
+        [first, rest @ ..] => {
+            let first_len = (header.stride as usize * header.height as usize).min(first.len());
+            let mut packed = first[..first_len].to_vec();
+            for plane in rest {
+                packed.extend_from_slice(plane);
+            }
+            Cow::Owned(packed)
+        }
+        [] => Cow::Borrowed(&[]),
+    }
+}
+
+fn mjpeg_to_bgr(jpeg: &[u8]) -> opencv::Result<Mat> {
+    let bgr = imdecode(&opencv::core::Vector::from_slice(jpeg), IMREAD_COLOR)?;
+    // OpenCV reports undecodable data as an empty image rather than an error.
+    if bgr.empty() {
+        return Err(opencv::Error::new(opencv::core::StsError, format!("MJPEG frame of {} bytes could not be decoded", jpeg.len())));
+    }
+    Ok(bgr)
+}
+
+fn nv12_to_bgr(data: &[u8], header: &FrameHeader) -> opencv::Result<Mat> {
+    if data.len() < header.min_payload_len() {
+        let message = format!("NV12 frame of {} bytes is too short for {}x{}", data.len(), header.width, header.height);
+        return Err(opencv::Error::new(opencv::core::StsBadSize, message));
+    }
+    // OpenCV takes NV12 as one single-channel image with the UV rows below the Y rows.
+    let nv12 = unsafe {
+        Mat::new_rows_cols_with_data(
+            header.height as i32 * 3 / 2,
+            header.width as i32,
+            opencv::core::CV_8UC1,
+            data.as_ptr() as *mut _,
+            header.stride as usize,
+        )?
+    };
+    let mut bgr = Mat::default();
+    cvt_color(&nv12, &mut bgr, COLOR_YUV2BGR_NV12, 0)?;
+    Ok(bgr)
+}
+
+/// Converts a captured YUYV frame into a BGR image.
+fn yuyv_to_bgr(data: &[u8], header: &FrameHeader) -> opencv::Result<Mat> {
+    if data.len() < header.min_payload_len() {
+        let message = format!("YUYV frame of {} bytes is too short for {}x{}", data.len(), header.width, header.height);
+        return Err(opencv::Error::new(opencv::core::StsBadSize, message));
+    }
+    let yuyv = unsafe {
+        Mat::new_rows_cols_with_data(
+            header.height as i32,
+            header.width as i32,
+            opencv::core::CV_8UC2,
+            data.as_ptr() as *mut _,
+            header.stride as usize,
+        )?
+    };
+    let mut bgr = Mat::default();
+    cvt_color(&yuyv, &mut bgr, COLOR_YUV2BGR_YUYV, 0)?;
+    Ok(bgr)
+}
+
+/// Reads an image file and scales it to `width`x`height`, returning its packed BGR pixels.
+pub fn read_bgr(path: &Path, width: u32, height: u32) -> opencv::Result<Vec<u8>> {
+    let image = imread(&path.to_string_lossy(), IMREAD_COLOR)?;
+    // Like `imdecode`, `imread` reports unreadable files as an empty image.
+    if image.empty() {
+        return Err(opencv::Error::new(opencv::core::StsError, "image could not be read".to_string()));
+    }
+    let mut resized = Mat::default();
+    resize(&image, &mut resized, Size::new(width as i32, height as i32), 0.0, 0.0, INTER_LINEAR)?;
+    Ok(resized.data_bytes()?.to_vec())
+}
+
+/// Rotates a BGR frame clockwise by `rotation` degrees, then flips and letterboxes it into
+/// the packed model input tensor, matching what the server does when it receives full frames.
+/// `letterbox` describes the rotated frame.
+pub fn letterbox_tensor(bgr: &Mat, rotation: u32, letterbox: &Letterbox) -> opencv::Result<Vec<u8>> {
+    let mut rotated = Mat::default();
+    let upright = match rotation {
+        90 => rotate(bgr, &mut rotated, ROTATE_90_CLOCKWISE).map(|_| &rotated)?,
+        180 => rotate(bgr, &mut rotated, ROTATE_180).map(|_| &rotated)?,
+        270 => rotate(bgr, &mut rotated, ROTATE_90_COUNTERCLOCKWISE).map(|_| &rotated)?,
+        _ => bgr,
+    };
+
+    let mut flipped = Mat::default();
+    let source = if letterbox.flipped {
+        flip(upright, &mut flipped, 1)?;
+        &flipped
+    } else {
+        upright
+    };
+
+    let mut resized = Mat::default();
+    let scaled = Size::new(letterbox.scaled_width as i32, letterbox.scaled_height as i32);
+    resize(source, &mut resized, scaled, 0.0, 0.0, INTER_LINEAR)?;
+
+    let top = letterbox.pad_top as i32;
+    let left = letterbox.pad_left as i32;
+    let bottom = (letterbox.target_height - letterbox.scaled_height) as i32 - top;
+    let right = (letterbox.target_width - letterbox.scaled_width) as i32 - left;
+    let mut padded = Mat::default();
+    copy_make_border(&resized, &mut padded, top, bottom, left, right, BORDER_CONSTANT, Scalar::all(0.0))?;
+
+    Ok(padded.data_bytes()?.to_vec())
+}
@@ -1,47 +1,75 @@
-use std::net::TcpStream;
-use std::io::{Read, Write};
-use serde::{Serialize, Deserialize};
-use bincode;
-
-#[derive(Serialize, Deserialize)]
-pub struct InferenceResult {
-    pub keypoints: Vec<f32>, // [1, 17, 3]
-}
+use crate::buffer::UserBuffer;
+use std::io::{Error, ErrorKind, Write};
+use std::net::{Shutdown, TcpStream};
+use rust_movenet_protocol::{
+    image_prefix, read_frame, read_message, write_image, write_message, ClientHello, FrameHeader, ResponseMode, ServerCapabilities,
+    ServerHello, ServerMessage,
+};
 
 pub struct ServerFacing {
     stream: TcpStream,
+    pub capabilities: ServerCapabilities,
+    response_mode: ResponseMode,
 }
 
 impl ServerFacing {
-    pub fn new(address: &str) -> std::io::Result<Self> {
-        let stream = TcpStream::connect(address)?;
-        Ok(ServerFacing { stream })
+    pub fn new(address: &str, hello: &ClientHello) -> std::io::Result<Self> {
+        let mut stream = TcpStream::connect(address)?;
+
+        write_message(&mut stream, hello)?;
+        let capabilities = match read_message(&mut stream)? {
+            ServerHello::Accepted(capabilities) => capabilities,
+            ServerHello::Refused { reason } => {
+                return Err(Error::new(ErrorKind::ConnectionRefused, format!("Server refused connection: {}", reason)));
+            }
+        };
+
+        println!(
+            "Connected to {} (protocol v{}, models: {:?})",
+            capabilities.server_name,
+            capabilities.version,
+            capabilities.models.iter().map(|m| m.name.as_str()).collect::<Vec<_>>()
+        );
+        Ok(ServerFacing { stream, capabilities, response_mode: hello.response_mode })
+    }
+
+    /// Returns a second handle on the same connection so sending and receiving can run on
+    /// separate threads.
+    pub fn try_clone(&self) -> std::io::Result<Self> {
+        Ok(ServerFacing {
+            stream: self.stream.try_clone()?,
+            capabilities: self.capabilities.clone(),
+            response_mode: self.response_mode,
+        })
+    }
+
+    /// Closes the connection, waking any thread blocked in [`ServerFacing::receive_result`].
+    pub fn shutdown(&self) -> std::io::Result<()> {
+        self.stream.shutdown(Shutdown::Both)
+    }
+
+    pub fn send_image(&mut self, header: &FrameHeader, image_bytes: &[u8]) -> std::io::Result<()> {
+        write_image(&mut self.stream, header, image_bytes)
     }
 
-    pub fn send_image(&mut self, image_bytes: &[u8]) -> std::io::Result<()> {
-        let len = (image_bytes.len() as u32).to_be_bytes();
-        self.stream.write_all(&len)?;
-        self.stream.write_all(image_bytes)?;
-        Ok(())
+    /// Sends a frame captured into `buffer`, writing the header into the room in front of the
+    /// image so the whole message goes out in one write without copying the image.
+    pub fn send_image_in_place(&mut self, header: &FrameHeader, buffer: &mut UserBuffer) -> std::io::Result<()> {
+        let prefix = image_prefix(header, buffer.image().len())?;
+        match buffer.with_prefix(&prefix) {
+            Some(message) => self.stream.write_all(message),
+            None => write_image(&mut self.stream, header, buffer.image()),
+        }
     }
 
-    pub fn receive_result(&mut self) -> std::io::Result<(InferenceResult, Vec<u8>)> {
-        // Receive keypoints data
-        let mut len_buf = [0u8; 4];
-        self.stream.read_exact(&mut len_buf)?;
-        let len = u32::from_be_bytes(len_buf) as usize;
-        let mut data_buf = vec![0u8; len];
-        self.stream.read_exact(&mut data_buf)?;
-        let result: InferenceResult = bincode::deserialize(&data_buf).map_err(|e| {
-            std::io::Error::new(std::io::ErrorKind::InvalidData, e)
-        })?;
-    
-        // Receive image data
-        self.stream.read_exact(&mut len_buf)?;
-        let img_len = u32::from_be_bytes(len_buf) as usize;
-        let mut img_buf = vec![0u8; img_len];
-        self.stream.read_exact(&mut img_buf)?;
-    
-        Ok((result, img_buf))
+    /// Reads the next message, along with the annotated JPEG that follows a result when the
+    /// negotiated mode includes one.
+    pub fn receive_result(&mut self) -> std::io::Result<(ServerMessage, Option<Vec<u8>>)> {
+        let message: ServerMessage = read_message(&mut self.stream)?;
+        let img_buf = match message {
+            ServerMessage::Result(_) if self.response_mode.includes_jpeg() => Some(read_frame(&mut self.stream)?),
+            _ => None,
+        };
+        Ok((message, img_buf))
     }
 }
@@ -0,0 +1,537 @@
+use crate::buffer::UserBuffer;
+use crate::camera::{monotonic_micros, Camera, Frame, FrameInfo};
+use crate::format::{PixFormat, V4L2_PIX_FMT_YUYV};
+use crate::preprocess;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Frame rate of the stand-in sources when neither `--fps` nor the file gives one.
+pub const DEFAULT_FPS: u32 = 30;
+
+/// File extensions [`ImageSource`] picks up, lowercase.
+const IMAGE_EXTENSIONS: [&str; 8] = ["bmp", "jpeg", "jpg", "png", "ppm", "tif", "tiff", "webp"];
+
+/// Somewhere frames come from. [`Camera`] is the real thing; the other sources stand in for it
+/// so the whole pipeline can run without a capture device, in CI for instance. They all
+/// deliver YUYV.
+pub trait FrameSource: Send {
+    /// The format every frame comes in.
+    fn format(&self) -> PixFormat;
+
+    /// Gets ready to deliver frames.
+    fn start(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    /// Stops delivering frames and frees whatever [`FrameSource::start`] set up.
+    fn stop(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    /// Waits for the next frame. A source that runs out of frames returns an error
+    /// [`is_end_of_stream`] recognizes.
+    fn next_frame(&mut self) -> Result<Frame<'_>, Box<dyn std::error::Error>>;
+
+    /// Hands over the next frame by swapping it into `buffer`, see [`Camera::take_frame`].
+    /// Returns what the source reported about the frame.
+    fn take_frame(&mut self, _buffer: &mut UserBuffer) -> Result<FrameInfo, Box<dyn std::error::Error>> {
+        Err(Box::new(Error::other("Only USERPTR cameras hand out their buffers")))
+    }
+}
+
+impl FrameSource for Camera {
+    fn format(&self) -> PixFormat {
+        Camera::format(self)
+    }
+
+    fn start(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.start_streaming()
+    }
+
+    /// Releases the buffers as well, so the device can be set up again, by a reopened
+    /// `Camera` for instance.
+    fn stop(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.release_buffers()
+    }
+
+    fn next_frame(&mut self) -> Result<Frame<'_>, Box<dyn std::error::Error>> {
+        self.get_frame()
+    }
+
+    fn take_frame(&mut self, buffer: &mut UserBuffer) -> Result<FrameInfo, Box<dyn std::error::Error>> {
+        Camera::take_frame(self, buffer)
+    }
+}
+
+/// Whether `error` is a source telling it has no frames left.
+pub fn is_end_of_stream(error: &(dyn std::error::Error + 'static)) -> bool {
+    error.downcast_ref::<Error>().is_some_and(|e| e.kind() == ErrorKind::UnexpectedEof)
+}
+
+fn end_of_stream() -> Box<dyn std::error::Error> {
+    Box::new(Error::new(ErrorKind::UnexpectedEof, "End of input"))
+}
+
+/// The format of `width`x`height` packed YUYV frames.
+fn yuyv_format(width: u32, height: u32) -> Result<PixFormat, Error> {
+    if width == 0 || height == 0 || !width.is_multiple_of(2) {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("{}x{} is not a valid YUYV frame size", width, height)));
+    }
+    Ok(PixFormat { width, height, fourcc: V4L2_PIX_FMT_YUYV, bytes_per_line: width * 2, size_image: width * height * 2, num_planes: 1, uniform_stride: true })
+}
+
+/// Spaces frames out to a steady rate and numbers them, the way a camera delivers them.
+struct Pacer {
+    interval: Duration,
+    next: Option<Instant>,
+    sequence: u32,
+}
+
+impl Pacer {
+    fn new(fps: u32) -> Self {
+        Pacer { interval: Duration::from_secs(1) / fps.max(1), next: None, sequence: 0 }
+    }
+
+    /// Sleeps until the next frame is due and returns its number and timestamp. A late frame
+    /// pushes the ones after it back rather than having them catch up in a burst.
+    fn wait(&mut self) -> FrameInfo {
+        let now = Instant::now();
+        let due = self.next.map_or(now, |next| next.max(now));
+        if due > now {
+            thread::sleep(due - now);
+        }
+        self.next = Some(due + self.interval);
+        let sequence = self.sequence;
+        self.sequence = sequence.wrapping_add(1);
+        FrameInfo { sequence, flags: 0, timestamp_us: monotonic_micros() }
+    }
+}
+
+/// Chroma subsampling of a Y4M file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Chroma {
+    C420,
+    C422,
+    C444,
+}
+
+impl Chroma {
+    /// Width and height of each chroma plane of a `width`x`height` image.
+    fn plane_size(self, width: usize, height: usize) -> (usize, usize) {
+        match self {
+            Chroma::C420 => (width.div_ceil(2), height.div_ceil(2)),
+            Chroma::C422 => (width.div_ceil(2), height),
+            Chroma::C444 => (width, height),
+        }
+    }
+}
+
+/// The stream header of a Y4M file, e.g. `YUV4MPEG2 W640 H480 F30:1 Ip A1:1 C420jpeg`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Y4mHeader {
+    width: u32,
+    height: u32,
+    /// Frame rate, rounded to whole frames per second.
+    fps: Option<u32>,
+    chroma: Chroma,
+}
+
+impl Y4mHeader {
+    fn parse(line: &str) -> Result<Self, Error> {
+        let invalid = |message: String| Error::new(ErrorKind::InvalidData, message);
+        let mut fields = line.split_ascii_whitespace();
+        if fields.next() != Some("YUV4MPEG2") {
+            return Err(invalid("Not a Y4M file".to_string()));
+        }
+
+        let (mut width, mut height, mut fps) = (None, None, None);
+        // Without a colorspace the spec says 4:2:0.
+        let mut chroma = Chroma::C420;
+        for field in fields {
+            let mut chars = field.chars();
+            let (tag, value) = (chars.next(), chars.as_str());
+            match tag {
+                Some('W') => width = value.parse().ok(),
+                Some('H') => height = value.parse().ok(),
+                Some('F') => {
+                    let (num, den) = value.split_once(':').ok_or_else(|| invalid(format!("Invalid Y4M frame rate {}", value)))?;
+                    let (num, den): (u32, u32) = (num.parse().unwrap_or(0), den.parse().unwrap_or(0));
+                    fps = (num > 0 && den > 0).then(|| ((num + den / 2) / den).max(1));
+                }
+                Some('C') => {
+                    chroma = match value {
+                        "420" | "420jpeg" | "420paldv" | "420mpeg2" => Chroma::C420,
+                        "422" => Chroma::C422,
+                        "444" => Chroma::C444,
+                        _ => return Err(invalid(format!("Unsupported Y4M colorspace {}; only 8-bit 4:2:0, 4:2:2 and 4:4:4 are", value))),
+                    };
+                }
+                _ => {}
+            }
+        }
+        match (width, height) {
+            (Some(width), Some(height)) => Ok(Y4mHeader { width, height, fps, chroma }),
+            _ => Err(invalid(format!("Y4M header without a frame size: {}", line.trim_end()))),
+        }
+    }
+}
+
+/// How a [`FileSource`] stores its frames.
+enum FileLayout {
+    /// Packed YUYV frames back to back, sized by `--width` and `--height`.
+    Raw,
+    /// Planar frames each preceded by a `FRAME` line, converted to YUYV as they are read.
+    Y4m(Chroma),
+}
+
+/// Plays back a file of raw YUYV frames, or a Y4M file.
+pub struct FileSource {
+    reader: BufReader<File>,
+    layout: FileLayout,
+    /// Where the first frame starts, to go back to when looping.
+    data_start: u64,
+    format: PixFormat,
+    looping: bool,
+    pacer: Pacer,
+    /// A Y4M frame as stored in the file.
+    planar: Vec<u8>,
+    /// The current frame in YUYV.
+    frame: Vec<u8>,
+}
+
+impl FileSource {
+    /// Opens `path`. A Y4M file carries its own frame size and rate; raw YUYV frames are taken
+    /// to be `width`x`height`. `fps` overrides the file's rate. With `looping` the file starts
+    /// over at the end instead of ending the stream.
+    pub fn open(path: &Path, width: u32, height: u32, fps: Option<u32>, looping: bool) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let is_y4m = reader.fill_buf()?.starts_with(b"YUV4MPEG2 ");
+        let (layout, data_start, format, fps) = if is_y4m {
+            let mut line = String::new();
+            let data_start = reader.read_line(&mut line)? as u64;
+            let header = Y4mHeader::parse(&line)?;
+            (FileLayout::Y4m(header.chroma), data_start, yuyv_format(header.width, header.height)?, fps.or(header.fps))
+        } else {
+            (FileLayout::Raw, 0, yuyv_format(width, height)?, fps)
+        };
+
+        let planar_len = match layout {
+            FileLayout::Raw => 0,
+            FileLayout::Y4m(chroma) => {
+                let (width, height) = (format.width as usize, format.height as usize);
+                let (chroma_width, chroma_height) = chroma.plane_size(width, height);
+                width * height + 2 * chroma_width * chroma_height
+            }
+        };
+        Ok(FileSource {
+            reader,
+            layout,
+            data_start,
+            format,
+            looping,
+            pacer: Pacer::new(fps.unwrap_or(DEFAULT_FPS)),
+            planar: vec![0; planar_len],
+            frame: vec![0; format.size_image as usize],
+        })
+    }
+
+    /// Reads the next frame into `self.frame`. Returns `false` at the end of the file, which
+    /// includes a last frame that was cut short.
+    fn read_frame(&mut self) -> Result<bool, Error> {
+        let read = match self.layout {
+            FileLayout::Raw => self.reader.read_exact(&mut self.frame),
+            FileLayout::Y4m(chroma) => {
+                let mut line = Vec::new();
+                if self.reader.read_until(b'\n', &mut line)? == 0 {
+                    return Ok(false);
+                }
+                if !line.starts_with(b"FRAME") {
+                    return Err(Error::new(ErrorKind::InvalidData, "Y4M frame without a FRAME header"));
+                }
+                let read = self.reader.read_exact(&mut self.planar);
+                if read.is_ok() {
+                    planar_to_yuyv(&self.planar, self.format.width as usize, self.format.height as usize, chroma, &mut self.frame);
+                }
+                read
+            }
+        };
+        match read {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl FrameSource for FileSource {
+    fn format(&self) -> PixFormat {
+        self.format
+    }
+
+    fn next_frame(&mut self) -> Result<Frame<'_>, Box<dyn std::error::Error>> {
+        if !self.read_frame()? {
+            if !self.looping {
+                return Err(end_of_stream());
+            }
+            self.reader.seek(SeekFrom::Start(self.data_start))?;
+            if !self.read_frame()? {
+                return Err(Box::new(Error::new(ErrorKind::InvalidData, "File holds no complete frame")));
+            }
+        }
+        let info = self.pacer.wait();
+        Ok(Frame::detached(vec![&self.frame], info))
+    }
+}
+
+/// Shows the images in a directory one after another, in name order, scaled to the frame size.
+pub struct ImageSource {
+    paths: Vec<PathBuf>,
+    next: usize,
+    format: PixFormat,
+    looping: bool,
+    pacer: Pacer,
+    frame: Vec<u8>,
+}
+
+impl ImageSource {
+    /// Lists the images in `dir`. They are read one at a time as frames are asked for.
+    pub fn open(dir: &Path, width: u32, height: u32, fps: Option<u32>, looping: bool) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|path| is_image(path))
+            .collect();
+        if paths.is_empty() {
+            return Err(format!("No images in {}", dir.display()).into());
+        }
+        paths.sort();
+        let format = yuyv_format(width, height)?;
+        Ok(ImageSource {
+            paths,
+            next: 0,
+            format,
+            looping,
+            pacer: Pacer::new(fps.unwrap_or(DEFAULT_FPS)),
+            frame: Vec::with_capacity(format.size_image as usize),
+        })
+    }
+}
+
+impl FrameSource for ImageSource {
+    fn format(&self) -> PixFormat {
+        self.format
+    }
+
+    fn next_frame(&mut self) -> Result<Frame<'_>, Box<dyn std::error::Error>> {
+        if self.next == self.paths.len() {
+            if !self.looping {
+                return Err(end_of_stream());
+            }
+            self.next = 0;
+        }
+        let path = &self.paths[self.next];
+        self.next += 1;
+
+        let (width, height) = (self.format.width, self.format.height);
+        let bgr = preprocess::read_bgr(path, width, height).map_err(|e| format!("{}: {}", path.display(), e))?;
+        bgr_to_yuyv(&bgr, width as usize, height as usize, &mut self.frame);
+        let info = self.pacer.wait();
+        Ok(Frame::detached(vec![&self.frame], info))
+    }
+}
+
+fn is_image(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| IMAGE_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str()))
+}
+
+/// Generates color bars over a black strip that a white block sweeps across, so consecutive
+/// frames differ.
+pub struct PatternSource {
+    format: PixFormat,
+    pacer: Pacer,
+    /// The frame without the block.
+    background: Vec<u8>,
+    frame: Vec<u8>,
+    count: usize,
+}
+
+impl PatternSource {
+    /// Colors of the bars from left to right, in BGR order.
+    const BARS: [[u8; 3]; 8] = [
+        [255, 255, 255],
+        [0, 255, 255],
+        [255, 255, 0],
+        [0, 255, 0],
+        [255, 0, 255],
+        [0, 0, 255],
+        [255, 0, 0],
+        [0, 0, 0],
+    ];
+    /// How far the block moves per frame, in pixels.
+    const STEP: usize = 8;
+
+    pub fn new(width: u32, height: u32, fps: Option<u32>) -> Result<Self, Box<dyn std::error::Error>> {
+        let format = yuyv_format(width, height)?;
+        let (width, height) = (width as usize, height as usize);
+        let bars_height = height - height / 8;
+        let mut bgr = vec![0; width * height * 3];
+        for pixels in bgr.chunks_exact_mut(width * 3).take(bars_height) {
+            for (x, pixel) in pixels.chunks_exact_mut(3).enumerate() {
+                pixel.copy_from_slice(&Self::BARS[x * Self::BARS.len() / width]);
+            }
+        }
+        let mut background = Vec::with_capacity(format.size_image as usize);
+        bgr_to_yuyv(&bgr, width, height, &mut background);
+        Ok(PatternSource {
+            format,
+            pacer: Pacer::new(fps.unwrap_or(DEFAULT_FPS)),
+            frame: background.clone(),
+            background,
+            count: 0,
+        })
+    }
+
+    /// Draws frame `count`: the background with the block at its position for that frame.
+    fn draw(&mut self) {
+        let (width, height) = (self.format.width as usize, self.format.height as usize);
+        // Blocks start on even pixels so they cover whole YUYV pairs.
+        let block_width = (width / 16).max(2) & !1;
+        let travel = width - block_width + 2;
+        let left = (self.count * Self::STEP % travel) & !1;
+
+        self.frame.copy_from_slice(&self.background);
+        let row_len = width * 2;
+        for row in self.frame.chunks_exact_mut(row_len).skip(height - height / 8) {
+            for pair in row[left * 2..(left + block_width) * 2].chunks_exact_mut(4) {
+                pair.copy_from_slice(&[235, 128, 235, 128]);
+            }
+        }
+    }
+}
+
+impl FrameSource for PatternSource {
+    fn format(&self) -> PixFormat {
+        self.format
+    }
+
+    fn next_frame(&mut self) -> Result<Frame<'_>, Box<dyn std::error::Error>> {
+        self.draw();
+        self.count += 1;
+        let info = self.pacer.wait();
+        Ok(Frame::detached(vec![&self.frame], info))
+    }
+}
+
+/// Packs a planar Y4M frame into YUYV, taking each pair of pixels' chroma from the chroma
+/// sample covering its left pixel.
+fn planar_to_yuyv(planar: &[u8], width: usize, height: usize, chroma: Chroma, yuyv: &mut Vec<u8>) {
+    let (chroma_width, chroma_height) = chroma.plane_size(width, height);
+    let (luma, rest) = planar.split_at(width * height);
+    let (u, v) = rest.split_at(chroma_width * chroma_height);
+
+    yuyv.clear();
+    for row in 0..height {
+        let y = &luma[row * width..(row + 1) * width];
+        let chroma_row = row * chroma_height / height * chroma_width;
+        for x in (0..width).step_by(2) {
+            let c = chroma_row + x * chroma_width / width;
+            yuyv.extend_from_slice(&[y[x], u[c], y[x + 1], v[c]]);
+        }
+    }
+}
+
+/// Converts packed BGR pixels into YUYV with the BT.601 studio-swing coefficients the server
+/// decodes YUYV with. Each pair of pixels shares the average of their chroma.
+fn bgr_to_yuyv(bgr: &[u8], width: usize, height: usize, yuyv: &mut Vec<u8>) {
+    let yuv = |pixel: &[u8]| {
+        let (b, g, r) = (pixel[0] as i32, pixel[1] as i32, pixel[2] as i32);
+        let y = ((66 * r + 129 * g + 25 * b + 128) >> 8) + 16;
+        let u = ((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128;
+        let v = ((112 * r - 94 * g - 18 * b + 128) >> 8) + 128;
+        (y, u, v)
+    };
+
+    yuyv.clear();
+    for pair in bgr[..width * height * 3].chunks_exact(6) {
+        let (y0, u0, v0) = yuv(&pair[..3]);
+        let (y1, u1, v1) = yuv(&pair[3..]);
+        yuyv.extend_from_slice(&[y0 as u8, ((u0 + u1 + 1) / 2) as u8, y1 as u8, ((v0 + v1 + 1) / 2) as u8]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn y4m_headers_give_size_rate_and_subsampling() {
+        let header = Y4mHeader::parse("YUV4MPEG2 W640 H480 F30000:1001 Ip A1:1 C422\n").unwrap();
+        assert_eq!(header, Y4mHeader { width: 640, height: 480, fps: Some(30), chroma: Chroma::C422 });
+
+        let header = Y4mHeader::parse("YUV4MPEG2 W8 H2").unwrap();
+        assert_eq!((header.fps, header.chroma), (None, Chroma::C420));
+
+        assert!(Y4mHeader::parse("YUV4MPEG2 W8 H2 C420p10").is_err());
+        assert!(Y4mHeader::parse("YUV4MPEG2 F25:1").is_err());
+    }
+
+    #[test]
+    fn planar_420_shares_chroma_between_rows() {
+        // 4x2 luma, one 2x1 row of chroma.
+        let planar = [0, 1, 2, 3, 4, 5, 6, 7, 10, 11, 20, 21];
+        let mut yuyv = Vec::new();
+        planar_to_yuyv(&planar, 4, 2, Chroma::C420, &mut yuyv);
+        assert_eq!(yuyv, [0, 10, 1, 20, 2, 11, 3, 21, 4, 10, 5, 20, 6, 11, 7, 21]);
+    }
+
+    #[test]
+    fn bgr_converts_to_studio_swing_yuyv() {
+        let mut yuyv = Vec::new();
+        bgr_to_yuyv(&[255, 255, 255, 0, 0, 0], 2, 1, &mut yuyv);
+        assert_eq!(yuyv, [235, 128, 16, 128]);
+    }
+
+    #[test]
+    fn pattern_frames_move() {
+        let mut pattern = PatternSource::new(64, 16, Some(1000)).unwrap();
+        let first = pattern.next_frame().unwrap().planes[0].to_vec();
+        let second = pattern.next_frame().unwrap().planes[0].to_vec();
+        assert_eq!(first.len(), 64 * 16 * 2);
+        // The top-left pixel is in the white bar.
+        assert_eq!(first[0], 235);
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn raw_files_loop_or_end() {
+        let path = std::env::temp_dir().join(format!("source_test_{}.yuv", std::process::id()));
+        let mut file = File::create(&path).unwrap();
+        // Two 2x1 frames and a partial third.
+        file.write_all(&[1, 2, 3, 4, 5, 6, 7, 8, 9]).unwrap();
+        drop(file);
+
+        let mut once = FileSource::open(&path, 2, 1, Some(1000), false).unwrap();
+        assert_eq!(once.next_frame().unwrap().planes[0], [1, 2, 3, 4]);
+        assert_eq!(once.next_frame().unwrap().planes[0], [5, 6, 7, 8]);
+        assert!(once.next_frame().is_err_and(|e| is_end_of_stream(&*e)));
+
+        let mut looping = FileSource::open(&path, 2, 1, Some(1000), true).unwrap();
+        looping.next_frame().unwrap();
+        looping.next_frame().unwrap();
+        assert_eq!(looping.next_frame().unwrap().planes[0], [1, 2, 3, 4]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn only_image_files_are_picked_up() {
+        assert!(is_image(Path::new("frames/0001.PNG")));
+        assert!(is_image(Path::new("frames/0002.jpg")));
+        assert!(!is_image(Path::new("frames/notes.txt")));
+        assert!(!is_image(Path::new("frames/README")));
+    }
+}
@@ -0,0 +1,202 @@
+use crate::messages::FrameHeader;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::{Error, ErrorKind, Read, Write};
+
+/// Upper bound on a single length-prefixed payload. A 4K YUYV frame is ~16 MiB,
+/// so anything past this is a corrupt stream rather than a real message.
+pub const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+
+pub fn encode<T: Serialize>(message: &T) -> std::io::Result<Vec<u8>> {
+    bincode::serialize(message).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+pub fn decode<T: DeserializeOwned>(bytes: &[u8]) -> std::io::Result<T> {
+    bincode::deserialize(bytes).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+/// Writes `payload` prefixed with its length as a big-endian `u32`.
+pub fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> std::io::Result<()> {
+    if payload.len() > MAX_FRAME_LEN {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("frame of {} bytes exceeds limit", payload.len())));
+    }
+    let len = (payload.len() as u32).to_be_bytes();
+    writer.write_all(&len)?;
+    writer.write_all(payload)?;
+    Ok(())
+}
+
+/// Reads one length-prefixed payload written by [`write_frame`].
+pub fn read_frame<R: Read>(reader: &mut R) -> std::io::Result<Vec<u8>> {
+    let mut len_buf = [0u8; 4];
+    reader.read_exact(&mut len_buf)?;
+    let len = u32::from_be_bytes(len_buf) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(Error::new(ErrorKind::InvalidData, format!("frame of {} bytes exceeds limit", len)));
+    }
+
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+pub fn write_message<W: Write, T: Serialize>(writer: &mut W, message: &T) -> std::io::Result<()> {
+    write_frame(writer, &encode(message)?)
+}
+
+pub fn read_message<R: Read, T: DeserializeOwned>(reader: &mut R) -> std::io::Result<T> {
+    decode(&read_frame(reader)?)
+}
+
+/// Writes a [`FrameHeader`] followed by the raw frame bytes.
+pub fn write_image<W: Write>(writer: &mut W, header: &FrameHeader, payload: &[u8]) -> std::io::Result<()> {
+    writer.write_all(&image_prefix(header, payload.len())?)?;
+    writer.write_all(payload)
+}
+
+/// Everything [`write_image`] sends ahead of a `payload_len`-byte payload: the framed header
+/// and the payload's length prefix. Callers that keep room in front of their frame buffer can
+/// copy this in place and send the whole message with a single write.
+pub fn image_prefix(header: &FrameHeader, payload_len: usize) -> std::io::Result<Vec<u8>> {
+    if payload_len > MAX_FRAME_LEN {
+        return Err(Error::new(ErrorKind::InvalidInput, format!("frame of {} bytes exceeds limit", payload_len)));
+    }
+    let mut prefix = Vec::new();
+    write_message(&mut prefix, header)?;
+    prefix.extend_from_slice(&(payload_len as u32).to_be_bytes());
+    Ok(prefix)
+}
+
+/// Reads a frame written by [`write_image`], rejecting payloads too short for the header's geometry.
+pub fn read_image<R: Read>(reader: &mut R) -> std::io::Result<(FrameHeader, Vec<u8>)> {
+    let header: FrameHeader = read_message(reader)?;
+    let payload = read_frame(reader)?;
+    if payload.len() < header.min_payload_len() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "frame {} is {} bytes, expected at least {} for {}x{} with stride {}",
+                header.sequence, payload.len(), header.min_payload_len(), header.width, header.height, header.stride
+            ),
+        ));
+    }
+    Ok((header, payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DropReason, InferenceResult, PixelFormat, ServerMessage};
+    use std::io::Cursor;
+
+    fn header(sequence: u64) -> FrameHeader {
+        FrameHeader {
+            sequence,
+            capture_timestamp_us: 1_000_000 + sequence,
+            width: 4,
+            height: 2,
+            stride: 8,
+            pixel_format: PixelFormat::Yuyv,
+            letterbox: None,
+        }
+    }
+
+    #[test]
+    fn frame_round_trip() {
+        let mut wire = Vec::new();
+        write_frame(&mut wire, b"first").unwrap();
+        write_frame(&mut wire, &[]).unwrap();
+        write_frame(&mut wire, &[0xAB; 1024]).unwrap();
+
+        assert_eq!(&wire[..4], &5u32.to_be_bytes());
+
+        let mut reader = Cursor::new(wire);
+        assert_eq!(read_frame(&mut reader).unwrap(), b"first");
+        assert!(read_frame(&mut reader).unwrap().is_empty());
+        assert_eq!(read_frame(&mut reader).unwrap(), vec![0xAB; 1024]);
+        assert_eq!(read_frame(&mut reader).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn message_round_trip() {
+        let result = InferenceResult {
+            sequence: 7,
+            keypoints: (0..51).map(|i| i as f32 / 51.0).collect(),
+            received_at_us: 10,
+            inferred_at_us: 20,
+            sent_at_us: 30,
+        };
+
+        let dropped = ServerMessage::Dropped { sequence: 8, reason: DropReason::Stale };
+
+        let mut wire = Vec::new();
+        write_message(&mut wire, &ServerMessage::Result(result.clone())).unwrap();
+        write_message(&mut wire, &dropped).unwrap();
+
+        let mut reader = Cursor::new(wire);
+        assert_eq!(read_message::<_, ServerMessage>(&mut reader).unwrap(), ServerMessage::Result(result));
+        assert_eq!(read_message::<_, ServerMessage>(&mut reader).unwrap(), dropped);
+    }
+
+    #[test]
+    fn image_round_trip() {
+        let mut wire = Vec::new();
+        write_image(&mut wire, &header(1), &[1; 16]).unwrap();
+        write_image(&mut wire, &header(2), &[2; 20]).unwrap();
+
+        let mut reader = Cursor::new(wire);
+        assert_eq!(read_image(&mut reader).unwrap(), (header(1), vec![1; 16]));
+        assert_eq!(read_image(&mut reader).unwrap(), (header(2), vec![2; 20]));
+    }
+
+    #[test]
+    fn prefix_and_payload_match_write_image() {
+        let mut wire = Vec::new();
+        write_image(&mut wire, &header(4), &[4; 16]).unwrap();
+
+        let mut prefixed = image_prefix(&header(4), 16).unwrap();
+        prefixed.extend_from_slice(&[4; 16]);
+        assert_eq!(prefixed, wire);
+    }
+
+    #[test]
+    fn short_image_is_rejected() {
+        let mut wire = Vec::new();
+        write_image(&mut wire, &header(3), &[0; 15]).unwrap();
+        assert_eq!(read_image(&mut Cursor::new(wire)).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn nv12_image_needs_its_chroma_plane() {
+        let nv12 = FrameHeader { pixel_format: PixelFormat::Nv12, ..header(5) };
+        let mut wire = Vec::new();
+        write_image(&mut wire, &nv12, &[0; 16]).unwrap();
+        write_image(&mut wire, &nv12, &[0; 24]).unwrap();
+
+        let mut reader = Cursor::new(wire);
+        assert_eq!(read_image(&mut reader).unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(read_image(&mut reader).unwrap().1.len(), 24);
+    }
+
+    #[test]
+    fn truncated_payload_is_an_error() {
+        let mut wire = Vec::new();
+        write_frame(&mut wire, b"truncated").unwrap();
+        wire.truncate(wire.len() - 1);
+        assert_eq!(read_frame(&mut Cursor::new(wire)).unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn oversized_length_is_rejected() {
+        let wire = (MAX_FRAME_LEN as u32 + 1).to_be_bytes().to_vec();
+        assert_eq!(read_frame(&mut Cursor::new(wire)).unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn garbage_message_is_invalid_data() {
+        let mut wire = Vec::new();
+        write_frame(&mut wire, &[1, 2]).unwrap();
+        let err = read_message::<_, InferenceResult>(&mut Cursor::new(wire)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+}
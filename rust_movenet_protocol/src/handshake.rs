@@ -0,0 +1,225 @@
+use crate::messages::{FrameHeader, PixelFormat, ResponseMode};
+use serde::{Deserialize, Serialize};
+
+/// Bumped whenever the wire format changes incompatibly.
+pub const PROTOCOL_VERSION: u32 = 7;
+
+/// First message on every connection, sent by the client.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ClientHello {
+    pub version: u32,
+    pub client_name: String,
+    pub width: u32,
+    pub height: u32,
+    pub pixel_format: PixelFormat,
+    pub response_mode: ResponseMode,
+}
+
+impl ClientHello {
+    /// Checks the geometry of a frame against what this hello negotiated. Frames can be
+    /// smaller than the negotiated size, since clients may send crops of it, but never larger
+    /// or empty, and every row has to hold `width` pixels. YUYV needs an even width, NV12 an
+    /// even width and height.
+    pub fn check_frame(&self, header: &FrameHeader) -> Result<(), String> {
+        if header.pixel_format != self.pixel_format {
+            return Err(format!("{:?} was not negotiated", header.pixel_format));
+        }
+        if header.width == 0 || header.height == 0 || header.width > self.width || header.height > self.height {
+            return Err(format!("{}x{} does not fit the negotiated {}x{}", header.width, header.height, self.width, self.height));
+        }
+        let (bytes_per_pixel, even_width, even_height) = match header.pixel_format {
+            PixelFormat::Yuyv => (2, true, false),
+            PixelFormat::Bgr24 => (3, false, false),
+            PixelFormat::Nv12 => (1, true, true),
+            // Compressed frames carry their own size; `stride` is unused.
+            PixelFormat::Mjpeg => return Ok(()),
+        };
+        if (even_width && !header.width.is_multiple_of(2)) || (even_height && !header.height.is_multiple_of(2)) {
+            return Err(format!("{}x{} is not a valid {:?} size", header.width, header.height, header.pixel_format));
+        }
+        if (header.stride as u64) < header.width as u64 * bytes_per_pixel {
+            return Err(format!("stride {} is too short for {} {:?} pixels", header.stride, header.width, header.pixel_format));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ModelInfo {
+    pub name: String,
+    pub input_width: u32,
+    pub input_height: u32,
+}
+
+/// What a server is able to do, advertised in reply to a [`ClientHello`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ServerCapabilities {
+    pub version: u32,
+    pub server_name: String,
+    pub models: Vec<ModelInfo>,
+    pub pixel_formats: Vec<PixelFormat>,
+    pub response_modes: Vec<ResponseMode>,
+    /// Clockwise rotation, 0, 90, 180 or 270 degrees, that turns frames upright for the model.
+    /// The server rotates full frames itself; clients sending tensors rotate them first.
+    pub rotation: u32,
+    /// Whether frames are mirrored horizontally before inference, like `rotation`.
+    pub flip: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ServerHello {
+    Accepted(ServerCapabilities),
+    Refused { reason: String },
+}
+
+impl ServerCapabilities {
+    /// Checks `hello` against what this server supports and returns the reply to send.
+    pub fn negotiate(&self, hello: &ClientHello) -> ServerHello {
+        match self.check(hello) {
+            Ok(()) => ServerHello::Accepted(self.clone()),
+            Err(reason) => ServerHello::Refused { reason },
+        }
+    }
+
+    fn check(&self, hello: &ClientHello) -> Result<(), String> {
+        if hello.version != self.version {
+            return Err(format!(
+                "protocol version {} is not supported (server speaks version {})",
+                hello.version, self.version
+            ));
+        }
+        if hello.width == 0 || hello.height == 0 {
+            return Err(format!("invalid frame size {}x{}", hello.width, hello.height));
+        }
+        if !self.pixel_formats.contains(&hello.pixel_format) {
+            return Err(format!("pixel format {:?} is not supported (server accepts {:?})", hello.pixel_format, self.pixel_formats));
+        }
+        if !self.response_modes.contains(&hello.response_mode) {
+            return Err(format!("response mode {:?} is not supported (server offers {:?})", hello.response_mode, self.response_modes));
+        }
+        if hello.pixel_format == PixelFormat::Bgr24 {
+            // The server never sees the full frame, so it has nothing to annotate.
+            if hello.response_mode != ResponseMode::KeypointsOnly {
+                return Err(format!("response mode {:?} needs full frames, not model tensors", hello.response_mode));
+            }
+            if !self.models.iter().any(|m| m.input_width == hello.width && m.input_height == hello.height) {
+                return Err(format!("no model takes a {}x{} input tensor", hello.width, hello.height));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{read_message, write_message};
+    use std::io::Cursor;
+
+    fn capabilities() -> ServerCapabilities {
+        ServerCapabilities {
+            version: PROTOCOL_VERSION,
+            server_name: "test".to_string(),
+            models: vec![ModelInfo { name: "movenet".to_string(), input_width: 192, input_height: 192 }],
+            pixel_formats: vec![PixelFormat::Yuyv],
+            response_modes: vec![ResponseMode::Both],
+            rotation: 0,
+            flip: true,
+        }
+    }
+
+    fn hello() -> ClientHello {
+        ClientHello {
+            version: PROTOCOL_VERSION,
+            client_name: "drone-1".to_string(),
+            width: 1920,
+            height: 1080,
+            pixel_format: PixelFormat::Yuyv,
+            response_mode: ResponseMode::Both,
+        }
+    }
+
+    fn refusal(reply: ServerHello) -> String {
+        match reply {
+            ServerHello::Refused { reason } => reason,
+            ServerHello::Accepted(_) => panic!("expected the hello to be refused"),
+        }
+    }
+
+    #[test]
+    fn hello_round_trip() {
+        let mut wire = Vec::new();
+        write_message(&mut wire, &hello()).unwrap();
+        write_message(&mut wire, &capabilities().negotiate(&hello())).unwrap();
+
+        let mut reader = Cursor::new(wire);
+        assert_eq!(read_message::<_, ClientHello>(&mut reader).unwrap(), hello());
+        assert_eq!(read_message::<_, ServerHello>(&mut reader).unwrap(), ServerHello::Accepted(capabilities()));
+    }
+
+    #[test]
+    fn version_mismatch_is_refused() {
+        let hello = ClientHello { version: PROTOCOL_VERSION + 1, ..hello() };
+        assert!(refusal(capabilities().negotiate(&hello)).contains("protocol version"));
+    }
+
+    #[test]
+    fn unsupported_response_mode_is_refused() {
+        let hello = ClientHello { response_mode: ResponseMode::KeypointsOnly, ..hello() };
+        assert!(refusal(capabilities().negotiate(&hello)).contains("KeypointsOnly"));
+    }
+
+    #[test]
+    fn frames_that_do_not_fit_the_hello_are_rejected() {
+        let hello = hello();
+        let frame = FrameHeader {
+            sequence: 1,
+            capture_timestamp_us: 0,
+            width: hello.width,
+            height: hello.height,
+            stride: hello.width * 2,
+            pixel_format: PixelFormat::Yuyv,
+            letterbox: None,
+        };
+        assert_eq!(hello.check_frame(&frame), Ok(()));
+        // A crop of the negotiated frame with the rows of the full one.
+        assert_eq!(hello.check_frame(&FrameHeader { width: 320, height: 240, ..frame }), Ok(()));
+
+        for bad in [
+            FrameHeader { stride: 0, ..frame },
+            FrameHeader { stride: hello.width * 2 - 2, ..frame },
+            FrameHeader { width: 0, height: 0, ..frame },
+            FrameHeader { height: hello.height + 1, ..frame },
+            FrameHeader { width: 321, ..frame },
+            FrameHeader { pixel_format: PixelFormat::Nv12, ..frame },
+        ] {
+            assert!(hello.check_frame(&bad).is_err(), "{:?} was accepted", bad);
+        }
+    }
+
+    #[test]
+    fn tensor_input_needs_keypoints_only_and_model_size() {
+        let capabilities = ServerCapabilities {
+            pixel_formats: vec![PixelFormat::Yuyv, PixelFormat::Bgr24],
+            response_modes: vec![ResponseMode::KeypointsOnly, ResponseMode::Both],
+            ..capabilities()
+        };
+        let tensor = ClientHello {
+            width: 192,
+            height: 192,
+            pixel_format: PixelFormat::Bgr24,
+            response_mode: ResponseMode::KeypointsOnly,
+            ..hello()
+        };
+
+        assert!(matches!(capabilities.negotiate(&tensor), ServerHello::Accepted(_)));
+        assert!(refusal(capabilities.negotiate(&ClientHello { response_mode: ResponseMode::Both, ..tensor.clone() })).contains("full frames"));
+        assert!(refusal(capabilities.negotiate(&ClientHello { width: 256, ..tensor })).contains("256x192"));
+    }
+
+    #[test]
+    fn empty_frame_is_refused() {
+        let hello = ClientHello { width: 0, ..hello() };
+        assert!(refusal(capabilities().negotiate(&hello)).contains("frame size"));
+    }
+}
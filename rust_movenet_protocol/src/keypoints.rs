@@ -0,0 +1,138 @@
+use serde::{Deserialize, Serialize};
+
+/// Number of keypoints MoveNet predicts for a single pose.
+pub const NUM_KEYPOINTS: usize = 17;
+
+/// Keypoint index pairs joined when drawing the skeleton.
+pub const SKELETON: [(usize, usize); 18] = [
+    (0, 1), (0, 2), (1, 3), (2, 4), // head
+    (0, 5), (0, 6), (5, 6), // shoulders
+    (5, 7), (7, 9), // left arm
+    (6, 8), (8, 10), // right arm
+    (5, 11), (6, 12), (11, 12), // body
+    (11, 13), (13, 15), // left leg
+    (12, 14), (14, 16), // right leg
+];
+
+/// How a source frame was fitted into the model input: optionally mirrored, scaled to
+/// `scaled_width` x `scaled_height` keeping its aspect ratio, then padded to the target size.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Letterbox {
+    pub source_width: u32,
+    pub source_height: u32,
+    pub target_width: u32,
+    pub target_height: u32,
+    pub scaled_width: u32,
+    pub scaled_height: u32,
+    pub pad_left: u32,
+    pub pad_top: u32,
+    pub flipped: bool,
+}
+
+impl Letterbox {
+    pub fn new(source_width: u32, source_height: u32, target_width: u32, target_height: u32, flipped: bool) -> Self {
+        let (scaled_width, scaled_height) = if source_width as f64 / source_height as f64 > target_width as f64 / target_height as f64 {
+            (target_width, (target_width as f64 / source_width as f64 * source_height as f64) as u32)
+        } else {
+            ((target_height as f64 / source_height as f64 * source_width as f64) as u32, target_height)
+        };
+
+        Letterbox {
+            source_width,
+            source_height,
+            target_width,
+            target_height,
+            scaled_width,
+            scaled_height,
+            pad_left: (target_width - scaled_width) / 2,
+            pad_top: (target_height - scaled_height) / 2,
+            flipped,
+        }
+    }
+
+    /// Rewrites MoveNet output (`[y, x, confidence]` triples normalized to the model input)
+    /// in place so that `y` and `x` are normalized to the source frame instead.
+    pub fn unmap_keypoints(&self, keypoints: &mut [f32]) {
+        for keypoint in keypoints.chunks_exact_mut(3) {
+            let y = (keypoint[0] * self.target_height as f32 - self.pad_top as f32) / self.scaled_height as f32;
+            let x = (keypoint[1] * self.target_width as f32 - self.pad_left as f32) / self.scaled_width as f32;
+            keypoint[0] = y;
+            keypoint[1] = if self.flipped { 1.0 - x } else { x };
+        }
+    }
+}
+
+/// Maps keypoint `index` of an [`InferenceResult`](crate::InferenceResult) to pixel coordinates
+/// in a `width` x `height` image of the frame. Returns `None` when the confidence is not above
+/// `threshold`.
+pub fn keypoint_to_pixel(keypoints: &[f32], index: usize, width: u32, height: u32, threshold: f32) -> Option<(i32, i32)> {
+    let y_ratio = keypoints[index * 3];
+    let x_ratio = keypoints[index * 3 + 1];
+    let confidence = keypoints[index * 3 + 2];
+    if confidence <= threshold {
+        return None;
+    }
+
+    Some(((x_ratio * width as f32) as i32, (y_ratio * height as f32) as i32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn single(y: f32, x: f32, confidence: f32) -> Vec<f32> {
+        let mut keypoints = vec![0.0; NUM_KEYPOINTS * 3];
+        keypoints[..3].copy_from_slice(&[y, x, confidence]);
+        keypoints
+    }
+
+    fn assert_close(actual: &[f32], expected: [f32; 3]) {
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-4, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn landscape_letterbox_pads_top_and_bottom() {
+        let letterbox = Letterbox::new(1920, 1080, 192, 192, true);
+        assert_eq!((letterbox.scaled_width, letterbox.scaled_height), (192, 108));
+        assert_eq!((letterbox.pad_left, letterbox.pad_top), (0, 42));
+    }
+
+    #[test]
+    fn portrait_letterbox_pads_left_and_right() {
+        let letterbox = Letterbox::new(1080, 1920, 192, 192, false);
+        assert_eq!((letterbox.scaled_width, letterbox.scaled_height), (108, 192));
+        assert_eq!((letterbox.pad_left, letterbox.pad_top), (42, 0));
+    }
+
+    #[test]
+    fn unmap_undoes_padding_and_flip() {
+        let letterbox = Letterbox::new(1920, 1080, 192, 192, true);
+        let mut keypoints = single(0.5, 0.25, 0.9);
+        letterbox.unmap_keypoints(&mut keypoints);
+        assert_close(&keypoints[..3], [0.5, 0.75, 0.9]);
+
+        let mut top_edge = single(42.0 / 192.0, 0.0, 0.9);
+        letterbox.unmap_keypoints(&mut top_edge);
+        assert_close(&top_edge[..3], [0.0, 1.0, 0.9]);
+    }
+
+    #[test]
+    fn unmap_without_flip_keeps_x() {
+        let letterbox = Letterbox::new(1080, 1920, 192, 192, false);
+        let mut keypoints = single(0.25, 0.5, 0.9);
+        letterbox.unmap_keypoints(&mut keypoints);
+        assert_close(&keypoints[..3], [0.25, 0.5, 0.9]);
+    }
+
+    #[test]
+    fn pixel_scales_to_image_size() {
+        assert_eq!(keypoint_to_pixel(&single(0.5, 0.75, 0.9), 0, 1920, 1080, 0.25), Some((1440, 540)));
+    }
+
+    #[test]
+    fn low_confidence_is_skipped() {
+        assert_eq!(keypoint_to_pixel(&single(0.5, 0.5, 0.25), 0, 1920, 1080, 0.25), None);
+    }
+}
@@ -0,0 +1,18 @@
+//! Wire types and framing shared by `rust_movenet_client` and `rust_movenet_server`.
+//!
+//! Every message on the socket is a big-endian `u32` length followed by that
+//! many bytes. Structured messages are bincode-encoded; raw payloads (camera
+//! frames, JPEG images) are written as-is.
+//!
+//! A connection opens with a [`ClientHello`] answered by a [`ServerHello`];
+//! frames only flow once the server has accepted.
+
+mod codec;
+mod handshake;
+mod keypoints;
+mod messages;
+
+pub use codec::*;
+pub use handshake::*;
+pub use keypoints::*;
+pub use messages::*;
@@ -0,0 +1,105 @@
+use crate::keypoints::Letterbox;
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    Yuyv,
+    /// Packed 8-bit BGR, the layout MoveNet takes as input. Used when the client
+    /// letterboxes frames itself and sends the model tensor directly.
+    Bgr24,
+    /// A full-resolution Y plane followed by a half-resolution plane of interleaved U and V
+    /// samples, both `stride` bytes per row.
+    Nv12,
+    /// A JPEG-compressed frame exactly as the camera produced it (`V4L2_PIX_FMT_MJPEG`).
+    /// The server decodes it; `stride` is unused.
+    Mjpeg,
+}
+
+/// What the server sends back for every frame. Every mode sends an [`InferenceResult`];
+/// `AnnotatedJpeg` leaves its keypoints empty, and the JPEG modes follow it with the image.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseMode {
+    KeypointsOnly,
+    AnnotatedJpeg,
+    Both,
+}
+
+impl ResponseMode {
+    pub fn includes_keypoints(self) -> bool {
+        self != ResponseMode::AnnotatedJpeg
+    }
+
+    pub fn includes_jpeg(self) -> bool {
+        self != ResponseMode::KeypointsOnly
+    }
+}
+
+/// Sent ahead of every frame payload.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub sequence: u64,
+    /// V4L2 capture time on the client's monotonic clock, in microseconds.
+    pub capture_timestamp_us: u64,
+    pub width: u32,
+    pub height: u32,
+    /// Bytes per row of the payload, including any driver padding.
+    pub stride: u32,
+    pub pixel_format: PixelFormat,
+    /// Present when the payload is an already letterboxed model tensor.
+    pub letterbox: Option<Letterbox>,
+}
+
+impl FrameHeader {
+    /// Smallest payload that can hold a frame with this geometry.
+    pub fn min_payload_len(&self) -> usize {
+        let luma = self.stride as usize * self.height as usize;
+        match self.pixel_format {
+            PixelFormat::Nv12 => luma + luma / 2,
+            PixelFormat::Yuyv | PixelFormat::Bgr24 => luma,
+            // Compressed frames have no size that follows from their geometry.
+            PixelFormat::Mjpeg => 0,
+        }
+    }
+}
+
+/// Keypoints are `[y, x, confidence]` triples with `y` and `x` normalized to the full
+/// captured frame, whichever side did the letterboxing.
+///
+/// Server timestamps are wall-clock microseconds on the server, so only the
+/// differences between them are meaningful to the client.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct InferenceResult {
+    pub sequence: u64,
+    pub keypoints: Vec<f32>, // [1, 17, 3]
+    pub received_at_us: u64,
+    pub inferred_at_us: u64,
+    pub sent_at_us: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    /// Pushed out of the server's frame queue by newer frames.
+    QueueFull,
+    /// Waited in the queue longer than the server's maximum frame age.
+    Stale,
+    /// The frame's format or geometry does not match what was negotiated.
+    Unsupported,
+    /// The compressed payload could not be decoded.
+    Corrupt,
+}
+
+/// Everything the server sends after the handshake. A `Result` is followed by its
+/// annotated JPEG when the negotiated [`ResponseMode`] includes one.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ServerMessage {
+    Result(InferenceResult),
+    Dropped { sequence: u64, reason: DropReason },
+}
+
+pub fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_micros() as u64)
+        .unwrap_or(0)
+}
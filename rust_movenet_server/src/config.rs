@@ -0,0 +1,198 @@
+use crate::queue::QueuePolicy;
+use clap::Parser;
+use serde::Deserialize;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Command-line flags. Anything given here overrides the config file.
+#[derive(Parser, Debug, Default)]
+#[command(about = "Runs MoveNet inference for rust_movenet_client connections")]
+pub struct Cli {
+    /// TOML file to read settings from before applying flags.
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+    /// Address to listen on, e.g. 0.0.0.0:7878.
+    #[arg(long)]
+    pub listen: Option<String>,
+    /// Path to the MoveNet .tflite model.
+    #[arg(long)]
+    pub model: Option<String>,
+    /// TensorFlow Lite interpreter threads; -1 lets TensorFlow Lite decide.
+    #[arg(long, allow_hyphen_values = true)]
+    pub threads: Option<i32>,
+    /// Minimum keypoint confidence drawn on annotated JPEGs.
+    #[arg(long)]
+    pub threshold: Option<f32>,
+    /// Mirror frames horizontally before inference. Clients that send tensors mirror them.
+    #[arg(long)]
+    pub flip: Option<bool>,
+    /// Clockwise rotation applied to frames before inference: 0, 90, 180 or 270. Clients that
+    /// send tensors rotate them.
+    #[arg(long)]
+    pub rotation: Option<u32>,
+    /// Quality of annotated JPEGs, 0-100.
+    #[arg(long)]
+    pub jpeg_quality: Option<i32>,
+    /// Maximum simultaneous clients; 0 means unlimited.
+    #[arg(long)]
+    pub max_clients: Option<usize>,
+    /// Frames each client may have waiting for inference.
+    #[arg(long)]
+    pub queue_capacity: Option<usize>,
+    /// What to do with a new frame when the client's queue is full.
+    #[arg(long, value_enum)]
+    pub queue_policy: Option<QueuePolicy>,
+    /// Drop frames that waited longer than this many milliseconds.
+    #[arg(long)]
+    pub max_frame_age_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen: String,
+    pub model_path: String,
+    pub threads: i32,
+    pub threshold: f32,
+    pub flip: bool,
+    pub rotation: u32,
+    pub jpeg_quality: i32,
+    pub max_clients: usize,
+    pub queue_capacity: usize,
+    pub queue_policy: QueuePolicy,
+    pub max_frame_age_ms: Option<u64>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: "10.66.83.44:7878".to_string(),
+            model_path: "resource/lite-model_movenet_singlepose_lightning_tflite_int8_4.tflite".to_string(),
+            threads: -1,
+            threshold: 0.25,
+            flip: true,
+            rotation: 0,
+            jpeg_quality: 95,
+            max_clients: 0,
+            queue_capacity: 1,
+            queue_policy: QueuePolicy::KeepLatest,
+            max_frame_age_ms: None,
+        }
+    }
+}
+
+impl Config {
+    /// Builds the configuration from the process arguments and the config file they name.
+    pub fn load() -> Result<Self, Box<dyn std::error::Error>> {
+        Self::from_cli(Cli::parse())
+    }
+
+    pub fn from_cli(cli: Cli) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut config = match &cli.config {
+            Some(path) => Self::from_toml(&std::fs::read_to_string(path)?)?,
+            None => Config::default(),
+        };
+
+        if let Some(listen) = cli.listen { config.listen = listen; }
+        if let Some(model) = cli.model { config.model_path = model; }
+        if let Some(threads) = cli.threads { config.threads = threads; }
+        if let Some(threshold) = cli.threshold { config.threshold = threshold; }
+        if let Some(flip) = cli.flip { config.flip = flip; }
+        if let Some(rotation) = cli.rotation { config.rotation = rotation; }
+        if let Some(jpeg_quality) = cli.jpeg_quality { config.jpeg_quality = jpeg_quality; }
+        if let Some(max_clients) = cli.max_clients { config.max_clients = max_clients; }
+        if let Some(queue_capacity) = cli.queue_capacity { config.queue_capacity = queue_capacity; }
+        if let Some(queue_policy) = cli.queue_policy { config.queue_policy = queue_policy; }
+        if cli.max_frame_age_ms.is_some() { config.max_frame_age_ms = cli.max_frame_age_ms; }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_toml(text: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(text)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if ![0, 90, 180, 270].contains(&self.rotation) {
+            return Err(format!("rotation must be 0, 90, 180 or 270, not {}", self.rotation));
+        }
+        if !(0..=100).contains(&self.jpeg_quality) {
+            return Err(format!("jpeg_quality must be between 0 and 100, not {}", self.jpeg_quality));
+        }
+        if self.threads < -1 {
+            return Err(format!("threads must be -1 or more, not {}", self.threads));
+        }
+        if self.queue_capacity == 0 {
+            return Err("queue_capacity must be at least 1".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn max_frame_age(&self) -> Option<Duration> {
+        self.max_frame_age_ms.map(Duration::from_millis)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_overrides_only_what_it_sets() {
+        let config = Config::from_toml(
+            r#"
+            listen = "0.0.0.0:9000"
+            threads = 4
+            queue_policy = "drop-oldest"
+            max_frame_age_ms = 200
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.listen, "0.0.0.0:9000");
+        assert_eq!(config.threads, 4);
+        assert_eq!(config.queue_policy, QueuePolicy::DropOldest);
+        assert_eq!(config.max_frame_age(), Some(Duration::from_millis(200)));
+        assert_eq!(config.model_path, Config::default().model_path);
+        assert!(config.flip);
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        assert!(Config::from_toml("treshold = 0.5").is_err());
+    }
+
+    #[test]
+    fn flags_override_the_file() {
+        let path = std::env::temp_dir().join(format!("rust_movenet_server_{}.toml", std::process::id()));
+        std::fs::write(&path, "threshold = 0.4\nflip = false\njpeg_quality = 80\n").unwrap();
+
+        let cli = Cli::try_parse_from([
+            "rust_movenet_server",
+            "--config",
+            path.to_str().unwrap(),
+            "--jpeg-quality",
+            "60",
+            "--threads",
+            "-1",
+            "--queue-policy",
+            "block",
+        ])
+        .unwrap();
+        let config = Config::from_cli(cli).unwrap();
+        std::fs::remove_file(&path).ok();
+
+        assert_eq!(config.threshold, 0.4);
+        assert!(!config.flip);
+        assert_eq!(config.jpeg_quality, 60);
+        assert_eq!(config.threads, -1);
+        assert_eq!(config.queue_policy, QueuePolicy::Block);
+    }
+
+    #[test]
+    fn invalid_rotation_is_rejected() {
+        let cli = Cli { rotation: Some(45), ..Cli::default() };
+        assert!(Config::from_cli(cli).unwrap_err().to_string().contains("rotation"));
+    }
+}
@@ -1,19 +1,54 @@
 use tflitec::interpreter::{Interpreter, Options};
 use std::sync::mpsc::{channel, Sender, Receiver};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 use std::net::{TcpListener, TcpStream};
-use serde::{Serialize, Deserialize};
-use opencv::core::{flip, Vec3b};
-use std::io::{Read, Write};
+use rust_movenet_protocol::{
+    now_micros, read_image, read_message, write_frame, write_message, ClientHello, DropReason, FrameHeader,
+    InferenceResult, Letterbox, ModelInfo, PixelFormat, ResponseMode, ServerCapabilities, ServerHello, ServerMessage,
+    PROTOCOL_VERSION,
+};
+use opencv::core::{flip, rotate, Vec3b, ROTATE_180, ROTATE_90_CLOCKWISE, ROTATE_90_COUNTERCLOCKWISE};
 use tflitec::model::Model;
 use opencv::prelude::*;
 use std::thread;
 
+mod config;
+mod queue;
 mod utils;
+use config::Config;
+use queue::FrameQueue;
 use utils::*;
 
-#[derive(Serialize, Deserialize)]
-struct InferenceResult {
-    keypoints: Vec<f32>, // [1, 17, 3]
+const MODEL_INPUT_SIZE: u32 = 192;
+
+struct ReceivedFrame {
+    header: FrameHeader,
+    payload: Vec<u8>,
+    received_at_us: u64,
+}
+
+/// A message for the send thread, with the annotated JPEG that follows a result.
+type Reply = (ServerMessage, Option<Vec<u8>>);
+
+/// Counts a connection against `max_clients` for as long as it is alive.
+struct ClientSlot {
+    active_clients: Arc<AtomicUsize>,
+}
+
+impl ClientSlot {
+    /// Claims a slot, returning the number of clients connected including this one.
+    fn claim(active_clients: &Arc<AtomicUsize>) -> (Self, usize) {
+        let count = active_clients.fetch_add(1, Ordering::SeqCst) + 1;
+        (ClientSlot { active_clients: Arc::clone(active_clients) }, count)
+    }
+}
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        self.active_clients.fetch_sub(1, Ordering::SeqCst);
+    }
 }
 
 struct ModelInterpreter {
@@ -33,140 +68,306 @@ impl ModelInterpreter {
     }
 }
 
-fn handle_client(stream: TcpStream, model_path: String, options: Options) {
+/// Reads the client's hello and answers it. `refusal` turns the client away regardless of
+/// what it asked for.
+fn handshake(stream: &mut TcpStream, capabilities: &ServerCapabilities, refusal: Option<String>) -> std::io::Result<Option<ClientHello>> {
+    stream.set_read_timeout(Some(std::time::Duration::from_secs(5)))?;
+    let hello: ClientHello = read_message(stream)?;
+    println!(
+        "Hello from {} (protocol v{}, {}x{} {:?}, {:?})",
+        hello.client_name, hello.version, hello.width, hello.height, hello.pixel_format, hello.response_mode
+    );
+
+    let reply = match refusal {
+        Some(reason) => ServerHello::Refused { reason },
+        None => capabilities.negotiate(&hello),
+    };
+    write_message(stream, &reply)?;
+    match reply {
+        ServerHello::Accepted(_) => Ok(Some(hello)),
+        ServerHello::Refused { reason } => {
+            println!("Refused {}: {}", hello.client_name, reason);
+            Ok(None)
+        }
+    }
+}
+
+fn handle_client(mut stream: TcpStream, config: Arc<Config>, capabilities: ServerCapabilities, active_clients: Arc<AtomicUsize>) {
     println!("New client connected");
-    let model_interpreter = ModelInterpreter::new(&model_path, options).expect("Failed to create ModelInterpreter");
+    let (_slot, client_count) = ClientSlot::claim(&active_clients);
+    let refusal = (config.max_clients > 0 && client_count > config.max_clients)
+        .then(|| format!("server is already serving its maximum of {} clients", config.max_clients));
 
-    let (frame_sender, frame_receiver) = channel();
+    let hello = match handshake(&mut stream, &capabilities, refusal) {
+        Ok(Some(hello)) => hello,
+        Ok(None) => return,
+        Err(e) => {
+            println!("Handshake failed: {:?}", e);
+            return;
+        }
+    };
+
+    let options = Options { thread_count: config.threads, ..Options::default() };
+    let model_interpreter = ModelInterpreter::new(&config.model_path, options).expect("Failed to create ModelInterpreter");
+
+    let frame_queue = Arc::new(FrameQueue::new(config.queue_capacity, config.queue_policy));
     let (result_sender, result_receiver) = channel();
 
     let stream_clone = stream.try_clone().unwrap();
+    let receive_queue = Arc::clone(&frame_queue);
+    let drop_sender = result_sender.clone();
     let receive_thread = thread::spawn(move || {
-        receive_frames(stream, frame_sender);
+        receive_frames(stream, receive_queue, drop_sender);
     });
 
     let process_thread = thread::spawn(move || {
-        process_frames(frame_receiver, result_sender, model_interpreter);
+        process_frames(frame_queue, result_sender, model_interpreter, hello, &config);
     });
 
     let send_thread = thread::spawn(move || {
         send_results(stream_clone, result_receiver);
     });
 
-    receive_thread.join().unwrap();
-    process_thread.join().unwrap();
-    send_thread.join().unwrap();
+    // The slot is only released once every thread is done, so a panic in one must not skip
+    // joining the others.
+    for (name, thread) in [("Receive", receive_thread), ("Processing", process_thread), ("Send", send_thread)] {
+        if thread.join().is_err() {
+            println!("{} thread panicked", name);
+        }
+    }
     println!("Client disconnected");
 }
 
-fn receive_frames(mut stream: TcpStream, frame_sender: Sender<Vec<u8>>) {
+fn receive_frames(mut stream: TcpStream, frame_queue: Arc<FrameQueue<ReceivedFrame>>, drop_sender: Sender<Reply>) {
     println!("Receive frames thread started");
     stream.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
     loop {
-        let mut len_buf = [0u8; 4];
-        match stream.read_exact(&mut len_buf) {
-            Ok(_) => {
-                let len = u32::from_be_bytes(len_buf) as usize;
-                
-                let mut img_buf = vec![0u8; len];
-                match stream.read_exact(&mut img_buf) {
-                    Ok(_) => {
-                        if frame_sender.send(img_buf).is_err() {
-                            println!("Error sending frame to processing thread");
-                            break;
-                        }
-                    },
-                    Err(e) => {
-                        println!("Error reading frame data: {:?}", e);
-                        break;
+        // Only a timeout before the first byte of a message can be retried. One partway
+        // through would leave the rest of the message to be read as the next, so it
+        // disconnects like any other error.
+        match stream.peek(&mut [0u8; 1]) {
+            Ok(0) => {
+                println!("Client disconnected: end of stream");
+                break;
+            }
+            Ok(_) => {}
+            Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => {
+                println!("Read timeout, continuing...");
+                continue;
+            }
+            Err(e) => {
+                println!("Client disconnected: {:?}", e);
+                break;
+            }
+        }
+        match read_image(&mut stream) {
+            Ok((header, payload)) => {
+                let frame = ReceivedFrame { header, payload, received_at_us: now_micros() };
+                let dropped = frame_queue.push(frame);
+                // A closed queue turns every frame away, which is not the overload QueueFull
+                // tells the client to back off from.
+                if frame_queue.is_closed() {
+                    println!("Processing thread stopped, no longer receiving frames");
+                    break;
+                }
+                for dropped in dropped {
+                    let message = ServerMessage::Dropped { sequence: dropped.header.sequence, reason: DropReason::QueueFull };
+                    if drop_sender.send((message, None)).is_err() {
+                        println!("Error reporting dropped frame to send thread");
                     }
                 }
             },
             Err(e) => {
-                if e.kind() == std::io::ErrorKind::WouldBlock {
-                    println!("Read timeout, continuing...");
-                    continue;
-                } else {
-                    println!("Client disconnected: {:?}", e);
-                    break;
-                }
+                println!("Client disconnected: {:?}", e);
+                break;
             }
         }
     }
+    frame_queue.close();
     println!("Receive frames thread ended");
 }
 
-fn process_frames(frame_receiver: Receiver<Vec<u8>>, result_sender: Sender<(InferenceResult, Vec<u8>)>, model_interpreter: ModelInterpreter) {
-    while let Ok(img_buf) = frame_receiver.recv() {
-        let rgb_frame = yuyv422_to_rgb(&img_buf);
-        let original_mat = unsafe {
-            Mat::new_rows_cols_with_data(
-                1080,
-                1920,
-                opencv::core::CV_8UC3,
-                rgb_frame.as_ptr() as *mut _,
-                opencv::core::Mat_AUTO_STEP
-            ).unwrap()
-        };
+fn process_frames(frame_queue: Arc<FrameQueue<ReceivedFrame>>, result_sender: Sender<Reply>, model_interpreter: ModelInterpreter, hello: ClientHello, config: &Config) {
+    let max_age = config.max_frame_age();
+    let mut jpeg_params = opencv::core::Vector::<i32>::new();
+    jpeg_params.push(opencv::imgcodecs::IMWRITE_JPEG_QUALITY);
+    jpeg_params.push(config.jpeg_quality);
+
+    // Lets the receive thread go if this thread stops early, e.g. because inference panicked.
+    let _closer = frame_queue.close_on_drop();
+    // The send thread only goes away with the client, when there is nobody left to tell.
+    let report_drop = |sequence, reason| {
+        result_sender.send((ServerMessage::Dropped { sequence, reason }, None)).ok();
+    };
 
-        let mut flipped = Mat::default();
-        flip(&original_mat, &mut flipped, 1).unwrap();
-        let resized_img = resize_with_padding(&flipped, [192, 192]);
-        let vec_2d: Vec<Vec<Vec3b>> = resized_img.to_vec_2d().unwrap();
-        let vec_1d: Vec<u8> = vec_2d.iter().flat_map(|v| v.iter().flat_map(|w| w.as_slice())).cloned().collect();
+    while let Some(frame) = frame_queue.pop() {
+        let header = frame.header;
+        let age = Duration::from_micros(now_micros().saturating_sub(frame.received_at_us));
+        if max_age.is_some_and(|max_age| age > max_age) {
+            report_drop(header.sequence, DropReason::Stale);
+            continue;
+        }
+        if let Err(reason) = hello.check_frame(&header) {
+            println!("Skipping frame {}: {}", header.sequence, reason);
+            report_drop(header.sequence, DropReason::Unsupported);
+            continue;
+        }
+
+        let rgb_frame;
+        let (vec_1d, original_mat, letterbox) = match (header.pixel_format, header.letterbox) {
+            (PixelFormat::Yuyv | PixelFormat::Nv12 | PixelFormat::Mjpeg, _) => {
+                let original_mat = if header.pixel_format == PixelFormat::Nv12 {
+                    nv12_to_rgb(&frame.payload, header.width as i32, header.height as i32, header.stride as usize)
+                } else if header.pixel_format == PixelFormat::Mjpeg {
+                    match decode_jpeg(&frame.payload) {
+                        Some(decoded) => decoded,
+                        None => {
+                            println!("Skipping frame {}: MJPEG payload of {} bytes could not be decoded", header.sequence, frame.payload.len());
+                            report_drop(header.sequence, DropReason::Corrupt);
+                            continue;
+                        }
+                    }
+                } else {
+                    // YUYV packs two pixels into four bytes, so each RGB row spans stride * 3 / 2 bytes,
+                    // padding included.
+                    rgb_frame = yuyv422_to_rgb(&frame.payload);
+                    unsafe {
+                        Mat::new_rows_cols_with_data(
+                            header.height as i32,
+                            header.width as i32,
+                            opencv::core::CV_8UC3,
+                            rgb_frame.as_ptr() as *mut _,
+                            header.stride as usize * 3 / 2
+                        ).unwrap()
+                    }
+                };
+
+                let mut rotated = Mat::default();
+                let upright = match config.rotation {
+                    90 => { rotate(&original_mat, &mut rotated, ROTATE_90_CLOCKWISE).unwrap(); &rotated }
+                    180 => { rotate(&original_mat, &mut rotated, ROTATE_180).unwrap(); &rotated }
+                    270 => { rotate(&original_mat, &mut rotated, ROTATE_90_COUNTERCLOCKWISE).unwrap(); &rotated }
+                    _ => &original_mat,
+                };
+
+                let letterbox = Letterbox::new(upright.cols() as u32, upright.rows() as u32, MODEL_INPUT_SIZE, MODEL_INPUT_SIZE, config.flip);
+                let mut flipped = Mat::default();
+                let model_view = if config.flip {
+                    flip(upright, &mut flipped, 1).unwrap();
+                    &flipped
+                } else {
+                    upright
+                };
+                let resized_img = resize_with_padding(model_view, &letterbox);
+                let vec_2d: Vec<Vec<Vec3b>> = resized_img.to_vec_2d().unwrap();
+                let vec_1d: Vec<u8> = vec_2d.iter().flat_map(|v| v.iter().flat_map(|w| w.as_slice())).cloned().collect();
+                (vec_1d, Some(original_mat), letterbox)
+            }
+            // The client already converted and letterboxed the frame; run on it as-is.
+            (PixelFormat::Bgr24, Some(letterbox)) if header.width == MODEL_INPUT_SIZE && header.height == MODEL_INPUT_SIZE => {
+                let row_len = header.width as usize * 3;
+                let Some(tensor) = pack_rows(&frame.payload, header.stride as usize, row_len, header.height as usize) else {
+                    println!("Skipping frame {}: stride {} does not fit {}-byte rows", header.sequence, header.stride, row_len);
+                    report_drop(header.sequence, DropReason::Unsupported);
+                    continue;
+                };
+                (tensor, None, letterbox)
+            }
+            (PixelFormat::Bgr24, _) => {
+                println!("Skipping frame {}: {}x{} tensor without letterbox parameters", header.sequence, header.width, header.height);
+                report_drop(header.sequence, DropReason::Unsupported);
+                continue;
+            }
+        };
 
         model_interpreter.interpreter.copy(&vec_1d[..], 0).unwrap();
         model_interpreter.interpreter.invoke().unwrap();
         let output_tensor = model_interpreter.interpreter.output(0).unwrap();
-        let keypoints = output_tensor.data::<f32>().to_vec();
+        let mut keypoints = output_tensor.data::<f32>().to_vec();
+        let inferred_at_us = now_micros();
+        letterbox.unmap_keypoints(&mut keypoints);
+        // Tensors were rotated by the client, as the capabilities told it to.
+        unrotate_keypoints(&mut keypoints, config.rotation);
 
-        let mut output_image = original_mat.clone();
-        draw_keypoints(&mut output_image, &keypoints, 0.25);
-        draw_connections(&mut output_image, &keypoints, 0.25); // Correct call to draw connections
+        // In keypoints-only mode the client draws on its own copy of the frame.
+        let img_bytes = match original_mat {
+            Some(original_mat) if hello.response_mode.includes_jpeg() => {
+                let mut output_image = original_mat.clone();
+                draw_keypoints(&mut output_image, &keypoints, config.threshold);
+                draw_connections(&mut output_image, &keypoints, config.threshold); // Correct call to draw connections
 
-        let mut img_buf = opencv::types::VectorOfu8::new();
-        opencv::imgcodecs::imencode(".jpg", &output_image, &mut img_buf, &opencv::core::Vector::new()).unwrap();
-        let img_bytes = img_buf.to_vec();
+                let mut img_buf = opencv::types::VectorOfu8::new();
+                opencv::imgcodecs::imencode(".jpg", &output_image, &mut img_buf, &jpeg_params).unwrap();
+                Some(img_buf.to_vec())
+            }
+            _ => None,
+        };
 
-        let result = InferenceResult { keypoints };
-        result_sender.send((result, img_bytes)).unwrap();
+        let result = InferenceResult {
+            sequence: header.sequence,
+            keypoints: if hello.response_mode.includes_keypoints() { keypoints } else { Vec::new() },
+            received_at_us: frame.received_at_us,
+            inferred_at_us,
+            sent_at_us: 0,
+        };
+        if result_sender.send((ServerMessage::Result(result), img_bytes)).is_err() {
+            break;
+        }
     }
 }
 
-fn send_results(mut stream: TcpStream, result_receiver: Receiver<(InferenceResult, Vec<u8>)>) {
+fn send_results(mut stream: TcpStream, result_receiver: Receiver<Reply>) {
     println!("Send frames thread started");
-    while let Ok((result, img_bytes)) = result_receiver.recv() {
-        
-        let serialized = bincode::serialize(&result).unwrap();
-        let result_len = (serialized.len() as u32).to_be_bytes();
-        if stream.write_all(&result_len).is_err() || stream.write_all(&serialized).is_err() {
+    while let Ok((mut message, img_bytes)) = result_receiver.recv() {
+        if let ServerMessage::Result(result) = &mut message {
+            result.sent_at_us = now_micros();
+        }
+        if write_message(&mut stream, &message).is_err() {
             println!("Error sending result data to client");
             break;
         }
 
-        let img_len = (img_bytes.len() as u32).to_be_bytes();
-        if stream.write_all(&img_len).is_err() || stream.write_all(&img_bytes).is_err() {
-            println!("Error sending image data to client");
-            break;
+        if let Some(img_bytes) = img_bytes {
+            if write_frame(&mut stream, &img_bytes).is_err() {
+                println!("Error sending image data to client");
+                break;
+            }
         }
     }
 }
 
+fn model_name(model_path: &str) -> String {
+    std::path::Path::new(model_path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| model_path.to_string())
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let options = Options::default();
-    let path = "resource/lite-model_movenet_singlepose_lightning_tflite_int8_4.tflite".to_string();
+    let config = Arc::new(Config::load()?);
+    let capabilities = ServerCapabilities {
+        version: PROTOCOL_VERSION,
+        server_name: "rust_movenet_server".to_string(),
+        models: vec![ModelInfo { name: model_name(&config.model_path), input_width: MODEL_INPUT_SIZE, input_height: MODEL_INPUT_SIZE }],
+        pixel_formats: vec![PixelFormat::Yuyv, PixelFormat::Nv12, PixelFormat::Mjpeg, PixelFormat::Bgr24],
+        response_modes: vec![ResponseMode::KeypointsOnly, ResponseMode::AnnotatedJpeg, ResponseMode::Both],
+        rotation: config.rotation,
+        flip: config.flip,
+    };
+    let active_clients = Arc::new(AtomicUsize::new(0));
 
-    let listener = TcpListener::bind("10.66.83.44:7878")?;
-    println!("Server listening on 10.66.83.44:7878");
+    let listener = TcpListener::bind(&config.listen)?;
+    println!("Server listening on {}", config.listen);
 
     for stream in listener.incoming() {
         match stream {
             Ok(stream) => {
-                let path_clone = path.clone();
-                let options_clone = options.clone();
+                let config_clone = Arc::clone(&config);
+                let capabilities_clone = capabilities.clone();
+                let active_clients_clone = Arc::clone(&active_clients);
                 thread::spawn(move || {
                     if let Err(e) = std::panic::catch_unwind(|| {
-                        handle_client(stream, path_clone, options_clone);
+                        handle_client(stream, config_clone, capabilities_clone, active_clients_clone);
                     }) {
                         eprintln!("Client thread panicked: {:?}", e);
                     }
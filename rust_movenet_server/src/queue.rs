@@ -0,0 +1,188 @@
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+
+/// What [`FrameQueue::push`] does when the queue is already full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum QueuePolicy {
+    /// Discard the oldest queued frame to make room.
+    DropOldest,
+    /// Discard everything queued; only the newest frame is ever waiting.
+    KeepLatest,
+    /// Wait for the processing thread to make room, pushing back on the client.
+    Block,
+}
+
+struct State<T> {
+    frames: VecDeque<T>,
+    closed: bool,
+}
+
+/// Bounded hand-off between a client's receive thread and its processing thread.
+pub struct FrameQueue<T> {
+    state: Mutex<State<T>>,
+    changed: Condvar,
+    capacity: usize,
+    policy: QueuePolicy,
+}
+
+impl<T> FrameQueue<T> {
+    pub fn new(capacity: usize, policy: QueuePolicy) -> Self {
+        FrameQueue {
+            state: Mutex::new(State { frames: VecDeque::new(), closed: false }),
+            changed: Condvar::new(),
+            capacity: capacity.max(1),
+            policy,
+        }
+    }
+
+    /// Queues `frame` and returns whatever the policy discarded to make room for it. A closed
+    /// queue discards `frame` itself, since nothing will take it any more.
+    pub fn push(&self, frame: T) -> Vec<T> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return vec![frame];
+        }
+        let mut dropped = Vec::new();
+        match self.policy {
+            QueuePolicy::DropOldest => {
+                while state.frames.len() >= self.capacity {
+                    dropped.extend(state.frames.pop_front());
+                }
+            }
+            QueuePolicy::KeepLatest => dropped.extend(state.frames.drain(..)),
+            QueuePolicy::Block => {
+                while state.frames.len() >= self.capacity && !state.closed {
+                    state = self.changed.wait(state).unwrap();
+                }
+                if state.closed {
+                    return vec![frame];
+                }
+            }
+        }
+        state.frames.push_back(frame);
+        self.changed.notify_all();
+        dropped
+    }
+
+    /// Blocks until a frame is available. Returns `None` once the queue is closed and drained.
+    pub fn pop(&self) -> Option<T> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(frame) = state.frames.pop_front() {
+                self.changed.notify_all();
+                return Some(frame);
+            }
+            if state.closed {
+                return None;
+            }
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+
+    /// Wakes every waiter; no more frames will be pushed.
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.changed.notify_all();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().closed
+    }
+
+    /// Returns a guard that closes the queue when it goes out of scope. A consumer holds one so
+    /// that however it stops, panics included, a producer blocked in [`FrameQueue::push`] is
+    /// released.
+    pub fn close_on_drop(&self) -> CloseOnDrop<'_, T> {
+        CloseOnDrop(self)
+    }
+}
+
+/// Closes a [`FrameQueue`] when dropped, see [`FrameQueue::close_on_drop`].
+pub struct CloseOnDrop<'a, T>(&'a FrameQueue<T>);
+
+impl<T> Drop for CloseOnDrop<'_, T> {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn drop_oldest_keeps_the_newest_frames() {
+        let queue = FrameQueue::new(2, QueuePolicy::DropOldest);
+        assert!(queue.push(1).is_empty());
+        assert!(queue.push(2).is_empty());
+        assert_eq!(queue.push(3), vec![1]);
+        assert_eq!(queue.pop(), Some(2));
+        assert_eq!(queue.pop(), Some(3));
+    }
+
+    #[test]
+    fn keep_latest_discards_everything_queued() {
+        let queue = FrameQueue::new(4, QueuePolicy::KeepLatest);
+        queue.push(1);
+        assert_eq!(queue.push(2), vec![1]);
+        assert_eq!(queue.push(3), vec![2]);
+        queue.close();
+        assert_eq!(queue.pop(), Some(3));
+        assert_eq!(queue.pop(), None);
+    }
+
+    #[test]
+    fn block_waits_for_room() {
+        let queue = Arc::new(FrameQueue::new(1, QueuePolicy::Block));
+        queue.push(1);
+
+        let producer = {
+            let queue = Arc::clone(&queue);
+            thread::spawn(move || queue.push(2))
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!producer.is_finished());
+
+        assert_eq!(queue.pop(), Some(1));
+        assert!(producer.join().unwrap().is_empty());
+        assert_eq!(queue.pop(), Some(2));
+    }
+
+    #[test]
+    fn consumer_panic_releases_a_blocked_producer() {
+        let queue = Arc::new(FrameQueue::new(1, QueuePolicy::Block));
+        queue.push(1);
+
+        let consumer = {
+            let queue = Arc::clone(&queue);
+            thread::spawn(move || {
+                let _closer = queue.close_on_drop();
+                thread::sleep(Duration::from_millis(50));
+                panic!("inference failed");
+            })
+        };
+        // Blocks until the consumer is gone, then gets its frame back.
+        assert_eq!(queue.push(2), vec![2]);
+        assert!(consumer.join().is_err());
+        assert!(queue.is_closed());
+        assert_eq!(queue.push(3), vec![3]);
+    }
+
+    #[test]
+    fn close_wakes_a_waiting_consumer() {
+        let queue = Arc::new(FrameQueue::<u32>::new(1, QueuePolicy::DropOldest));
+        let consumer = {
+            let queue = Arc::clone(&queue);
+            thread::spawn(move || queue.pop())
+        };
+        thread::sleep(Duration::from_millis(50));
+        queue.close();
+        assert_eq!(consumer.join().unwrap(), None);
+    }
+}
@@ -3,18 +3,11 @@ use opencv::{
 	imgproc::*,
 	core::*,
 };
+use rust_movenet_protocol::{keypoint_to_pixel, Letterbox, NUM_KEYPOINTS, SKELETON};
 
-pub fn resize_with_padding(img: &Mat, new_shape: [i32;2]) -> Mat {
-	let img_shape = [img.cols(), img.rows()];
-	let width: i32;
-	let height: i32;
-	if img_shape[0] as f64 / img_shape[1] as f64 > new_shape[0] as f64 / new_shape[1] as f64 {
-		width = new_shape[0];
-		height = (new_shape[0] as f64 / img_shape[0] as f64 * img_shape[1] as f64) as i32;
-	} else {
-		width = (new_shape[1] as f64 / img_shape[1] as f64 * img_shape[0] as f64) as i32;
-		height = new_shape[1];
-	}
+pub fn resize_with_padding(img: &Mat, letterbox: &Letterbox) -> Mat {
+	let width = letterbox.scaled_width as i32;
+	let height = letterbox.scaled_height as i32;
 
 	let mut resized = Mat::default();
 	resize(
@@ -25,10 +18,10 @@ pub fn resize_with_padding(img: &Mat, new_shape: [i32;2]) -> Mat {
 		INTER_LINEAR)
 		.expect("resize_with_padding: resize [FAILED]");
 
-	let delta_w = new_shape[0] - width;
-	let delta_h = new_shape[1] - height;
-	let (top, bottom) = (delta_h / 2, delta_h - delta_h / 2);
-	let (left, right) = (delta_w / 2, delta_w - delta_w / 2);
+	let delta_w = letterbox.target_width as i32 - width;
+	let delta_h = letterbox.target_height as i32 - height;
+	let (top, bottom) = (letterbox.pad_top as i32, delta_h - letterbox.pad_top as i32);
+	let (left, right) = (letterbox.pad_left as i32, delta_w - letterbox.pad_left as i32);
 		
 	let mut rslt = Mat::default();
 	copy_make_border(
@@ -41,6 +34,38 @@ pub fn resize_with_padding(img: &Mat, new_shape: [i32;2]) -> Mat {
 	rslt
 }
 
+/// Copies `height` rows of `row_len` bytes out of a buffer whose rows start `stride` bytes apart.
+/// Returns `None` if the rows overlap or `data` is too short to hold them.
+pub fn pack_rows(data: &[u8], stride: usize, row_len: usize, height: usize) -> Option<Vec<u8>> {
+    if stride == 0 || stride < row_len || height == 0 || data.len() < stride * (height - 1) + row_len {
+        return None;
+    }
+    if stride == row_len {
+        return Some(data[..row_len * height].to_vec());
+    }
+    let mut packed = Vec::with_capacity(row_len * height);
+    for row in data.chunks(stride).take(height) {
+        packed.extend_from_slice(&row[..row_len]);
+    }
+    Some(packed)
+}
+
+/// Maps keypoints normalized to a frame rotated clockwise by `rotation` degrees back onto
+/// the frame as captured.
+pub fn unrotate_keypoints(keypoints: &mut [f32], rotation: u32) {
+    for keypoint in keypoints.chunks_exact_mut(3) {
+        let (y, x) = (keypoint[0], keypoint[1]);
+        let (original_y, original_x) = match rotation {
+            90 => (1.0 - x, y),
+            180 => (1.0 - y, 1.0 - x),
+            270 => (x, 1.0 - y),
+            _ => (y, x),
+        };
+        keypoint[0] = original_y;
+        keypoint[1] = original_x;
+    }
+}
+
 pub fn yuyv422_to_rgb(yuyv: &[u8]) -> Vec<u8> {
     let mut rgb = vec![0u8; yuyv.len() * 3 / 2];
     for i in 0..(yuyv.len() / 4) {
@@ -72,31 +97,34 @@ pub fn yuyv422_to_rgb(yuyv: &[u8]) -> Vec<u8> {
     rgb
 }
 
+/// Converts an NV12 frame, a Y plane followed by an interleaved UV plane at half resolution
+/// with rows `stride` bytes apart. Like `yuyv422_to_rgb`, the channels come out in BGR order.
+pub fn nv12_to_rgb(nv12: &[u8], width: i32, height: i32, stride: usize) -> Mat {
+    let yuv = unsafe {
+        Mat::new_rows_cols_with_data(height * 3 / 2, width, CV_8UC1, nv12.as_ptr() as *mut _, stride)
+            .expect("nv12_to_rgb: Mat [FAILED]")
+    };
+    let mut rgb = Mat::default();
+    cvt_color(&yuv, &mut rgb, COLOR_YUV2BGR_NV12, 0).expect("nv12_to_rgb: cvt_color [FAILED]");
+    rgb
+}
+
+/// Decodes a JPEG, such as one MJPEG frame, into a BGR image. Returns `None` for data that
+/// is not a complete JPEG; cameras occasionally deliver truncated frames.
+pub fn decode_jpeg(jpeg: &[u8]) -> Option<Mat> {
+    let data = opencv::types::VectorOfu8::from_slice(jpeg);
+    let decoded = opencv::imgcodecs::imdecode(&data, opencv::imgcodecs::IMREAD_COLOR).ok()?;
+    (!decoded.empty()).then_some(decoded)
+}
+
 pub fn draw_connections(img: &mut Mat, keypoints: &[f32], threshold: f32) {
-    let base: f32 = img.rows().max(img.cols()) as f32;
-    let pad_x: i32 = if img.rows() > img.cols() { (img.rows() - img.cols()) / 2 } else { 0 };
-    let pad_y: i32 = if img.cols() > img.rows() { (img.cols() - img.rows()) / 2 } else { 0 };
-
-    let connections = [
-        (0, 1), (0, 2), (1, 3), (2, 4), // head
-        (0, 5), (0, 6), (5, 6), // shoulders
-        (5, 7), (7, 9), // left arm
-        (6, 8),  (8, 10), // right arm
-        (5, 11), (6, 12), (11, 12), // body
-        (11, 13), (13, 15), // left leg
-        (12, 14), (14, 16), // right leg
-    ];
-
-    for &(start, end) in &connections {
-        let start_confidence = keypoints[start * 3 + 2];
-        let end_confidence = keypoints[end * 3 + 2];
-
-        if start_confidence > threshold && end_confidence > threshold {
-            let start_x = base as i32 - ((keypoints[start * 3 + 1] * base) as i32 - pad_x);
-            let start_y = (keypoints[start * 3] * base) as i32 - pad_y;
-            let end_x = base as i32 - ((keypoints[end * 3 + 1] * base) as i32 - pad_x);
-            let end_y = (keypoints[end * 3] * base) as i32 - pad_y;
+    let (width, height) = (img.cols() as u32, img.rows() as u32);
+
+    for &(start, end) in &SKELETON {
+        let start_point = keypoint_to_pixel(keypoints, start, width, height, threshold);
+        let end_point = keypoint_to_pixel(keypoints, end, width, height, threshold);
 
+        if let (Some((start_x, start_y)), Some((end_x, end_y))) = (start_point, end_point) {
             line(img, 
                  Point::new(start_x, start_y),
                  Point::new(end_x, end_y),
@@ -109,42 +137,15 @@ pub fn draw_connections(img: &mut Mat, keypoints: &[f32], threshold: f32) {
 }
 
 pub fn draw_keypoints(img: &mut Mat, keypoints: &[f32], threshold: f32) {
-    let base: f32;
-    let pad_x: i32;
-    let pad_y: i32;
-
-    if img.rows() > img.cols() {
-        base = img.rows() as f32;
-        pad_x = (img.rows() - img.cols()) / 2;
-        pad_y = 0;
-    } else {
-        base = img.cols() as f32;
-        pad_x = 0;
-        pad_y = (img.cols() - img.rows()) / 2;
-    }
-
-    let mut points = Vec::new();
-    for index in 0..17 {
-        let y_ratio = keypoints[index * 3];
-        let x_ratio = keypoints[index * 3 + 1];
-        let confidence = keypoints[index * 3 + 2];
-
-        if confidence > threshold {
-            // Adjust the x-coordinate to account for the flip
-            let adjusted_x = base as i32 - ((x_ratio * base) as i32 - pad_x);
-            let adjusted_y = (y_ratio * base) as i32 - pad_y;
+    let (width, height) = (img.cols() as u32, img.rows() as u32);
 
+    for index in 0..NUM_KEYPOINTS {
+        if let Some((x, y)) = keypoint_to_pixel(keypoints, index, width, height, threshold) {
             circle(img,
-                Point { x: adjusted_x, y: adjusted_y },
+                Point { x, y },
                 5, // Circle radius
                 Scalar::new(0.0, 0.0, 255.0, 0.0), // Red color for points
                 -1, LINE_AA, 0).expect("Draw circle [FAILED]");
-
-            // Store the point for drawing lines later
-            points.push(Point { x: adjusted_x, y: adjusted_y });
-        } else {
-            // Add a placeholder point if confidence is below threshold
-            points.push(Point { x: -1, y: -1 });
         }
     }
 }